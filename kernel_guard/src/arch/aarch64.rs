@@ -1,5 +1,8 @@
 use core::arch::asm;
 
+/// Bit 7: IRQ mask bit of `DAIF`
+const DAIF_I_BIT: usize = 1 << 7;
+
 #[inline]
 pub fn local_irq_save_and_disable() -> usize {
     let flags: usize;
@@ -12,3 +15,61 @@ pub fn local_irq_save_and_disable() -> usize {
 pub fn local_irq_restore(flags: usize) {
     unsafe { asm!("msr daif, {}", in(reg) flags) };
 }
+
+/// Names of the bits kept in the saved IRQ state.
+pub const IRQ_STATE_BITS: &[(&str, usize)] = &[
+    ("D", 1 << 9),
+    ("A", 1 << 8),
+    ("I", DAIF_I_BIT),
+    ("F", 1 << 6),
+];
+
+#[inline]
+pub const fn irq_state_enabled(flags: usize) -> bool {
+    flags & DAIF_I_BIT == 0
+}
+
+#[inline]
+pub fn local_irq_enable() {
+    unsafe { asm!("msr daifclr, #2") };
+}
+
+#[inline]
+pub fn local_irq_disable() {
+    unsafe { asm!("msr daifset, #2") };
+}
+
+#[inline]
+pub fn irqs_enabled() -> bool {
+    let flags: usize;
+    unsafe { asm!("mrs {}, daif", out(reg) flags) };
+    flags & DAIF_I_BIT == 0
+}
+
+/// Returns the base address of the per-CPU data, which is saved in
+/// `TPIDR_EL1`.
+#[cfg(feature = "percpu")]
+#[inline]
+pub fn local_percpu_base() -> usize {
+    let base: usize;
+    unsafe { asm!("mrs {}, tpidr_el1", out(reg) base) };
+    base
+}
+
+/// Reads the virtual count of the generic timer (`CNTVCT_EL0`).
+#[cfg(any(feature = "irqsoff-trace", feature = "preemptoff-trace"))]
+#[inline]
+pub fn read_timestamp() -> usize {
+    let count: usize;
+    unsafe { asm!("isb; mrs {}, cntvct_el0", out(reg) count, options(nomem, nostack)) };
+    count
+}
+
+/// Reads the frequency of the generic timer (`CNTFRQ_EL0`).
+#[cfg(feature = "watchdog")]
+#[inline]
+pub fn default_timestamp_frequency() -> usize {
+    let freq: usize;
+    unsafe { asm!("mrs {}, cntfrq_el0", out(reg) freq, options(nomem, nostack)) };
+    freq
+}
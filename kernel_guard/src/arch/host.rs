@@ -0,0 +1,100 @@
+//! Emulation for hosted targets, where the local IRQ state is just a flag.
+//!
+//! With the feature `std`, each thread acts as a CPU and owns its flag.
+//! Otherwise, all threads share one emulated CPU.
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "std")] {
+        extern crate std;
+
+        use core::cell::Cell;
+
+        std::thread_local! {
+            static IRQS_ENABLED: Cell<bool> = const { Cell::new(true) };
+        }
+
+        #[inline]
+        fn replace_irqs_enabled(enabled: bool) -> bool {
+            IRQS_ENABLED.with(|flag| flag.replace(enabled))
+        }
+
+        #[inline]
+        pub fn irqs_enabled() -> bool {
+            IRQS_ENABLED.with(|flag| flag.get())
+        }
+    } else {
+        use core::sync::atomic::{AtomicBool, Ordering};
+
+        static IRQS_ENABLED: AtomicBool = AtomicBool::new(true);
+
+        #[inline]
+        fn replace_irqs_enabled(enabled: bool) -> bool {
+            IRQS_ENABLED.swap(enabled, Ordering::Relaxed)
+        }
+
+        #[inline]
+        pub fn irqs_enabled() -> bool {
+            IRQS_ENABLED.load(Ordering::Relaxed)
+        }
+    }
+}
+
+#[inline]
+pub fn local_irq_save_and_disable() -> usize {
+    replace_irqs_enabled(false) as usize
+}
+
+#[inline]
+pub fn local_irq_restore(flags: usize) {
+    replace_irqs_enabled(flags != 0);
+}
+
+/// Names of the bits kept in the saved IRQ state.
+pub const IRQ_STATE_BITS: &[(&str, usize)] = &[("IRQ", 1)];
+
+#[inline]
+pub const fn irq_state_enabled(flags: usize) -> bool {
+    flags != 0
+}
+
+#[inline]
+pub fn local_irq_enable() {
+    replace_irqs_enabled(true);
+}
+
+#[inline]
+pub fn local_irq_disable() {
+    replace_irqs_enabled(false);
+}
+
+/// Reads the nanoseconds elapsed since the first call.
+#[cfg(all(
+    any(feature = "irqsoff-trace", feature = "preemptoff-trace"),
+    feature = "std"
+))]
+#[inline]
+pub fn read_timestamp() -> usize {
+    static START: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+    START
+        .get_or_init(std::time::Instant::now)
+        .elapsed()
+        .as_nanos() as usize
+}
+
+/// There is no clock without the feature `std`, so the timestamps are always
+/// zero.
+#[cfg(all(
+    any(feature = "irqsoff-trace", feature = "preemptoff-trace"),
+    not(feature = "std")
+))]
+#[inline]
+pub fn read_timestamp() -> usize {
+    0
+}
+
+/// The timestamps are in nanoseconds.
+#[cfg(feature = "watchdog")]
+#[inline]
+pub fn default_timestamp_frequency() -> usize {
+    1_000_000_000
+}
@@ -1,7 +1,10 @@
 #![cfg_attr(not(target_os = "none"), allow(dead_code))]
 
 cfg_if::cfg_if! {
-    if #[cfg(any(target_arch = "x86", target_arch = "x86_64"))] {
+    if #[cfg(not(target_os = "none"))] {
+        mod host;
+        pub use self::host::*;
+    } else if #[cfg(any(target_arch = "x86", target_arch = "x86_64"))] {
         mod x86;
         pub use self::x86::*;
     } else if #[cfg(any(target_arch = "riscv32", target_arch = "riscv64"))] {
@@ -16,3 +16,53 @@ pub fn local_irq_restore(flags: usize) {
     // restore the `SIE` bit
     unsafe { asm!("csrrs x0, sstatus, {}", in(reg) flags) };
 }
+
+/// Names of the bits kept in the saved IRQ state.
+pub const IRQ_STATE_BITS: &[(&str, usize)] = &[("SIE", SIE_BIT)];
+
+#[inline]
+pub const fn irq_state_enabled(flags: usize) -> bool {
+    flags & SIE_BIT != 0
+}
+
+#[inline]
+pub fn local_irq_enable() {
+    unsafe { asm!("csrs sstatus, {}", const SIE_BIT) };
+}
+
+#[inline]
+pub fn local_irq_disable() {
+    unsafe { asm!("csrc sstatus, {}", const SIE_BIT) };
+}
+
+#[inline]
+pub fn irqs_enabled() -> bool {
+    let flags: usize;
+    unsafe { asm!("csrr {}, sstatus", out(reg) flags) };
+    flags & SIE_BIT != 0
+}
+
+/// Returns the base address of the per-CPU data, which is saved in `tp`.
+#[cfg(feature = "percpu")]
+#[inline]
+pub fn local_percpu_base() -> usize {
+    let base: usize;
+    unsafe { asm!("mv {}, tp", out(reg) base) };
+    base
+}
+
+/// Reads the `time` CSR. Only the lower 32 bits are read on RV32.
+#[cfg(any(feature = "irqsoff-trace", feature = "preemptoff-trace"))]
+#[inline]
+pub fn read_timestamp() -> usize {
+    let time: usize;
+    unsafe { asm!("rdtime {}", out(reg) time, options(nomem, nostack)) };
+    time
+}
+
+/// The timebase frequency is unknown, which must be set by the kernel.
+#[cfg(feature = "watchdog")]
+#[inline]
+pub fn default_timestamp_frequency() -> usize {
+    0
+}
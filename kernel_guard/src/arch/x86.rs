@@ -1,5 +1,8 @@
 use core::arch::asm;
 
+#[cfg(feature = "preempt-count")]
+use crate::percpu::{NEED_RESCHED_OFFSET, PREEMPT_COUNT_OFFSET};
+
 /// Interrupt Enable Flag (IF)
 const IF_BIT: usize = 1 << 9;
 
@@ -18,3 +21,125 @@ pub fn local_irq_restore(flags: usize) {
         unsafe { asm!("cli") };
     }
 }
+
+/// Names of the bits kept in the saved IRQ state.
+pub const IRQ_STATE_BITS: &[(&str, usize)] = &[("IF", IF_BIT)];
+
+#[inline]
+pub const fn irq_state_enabled(flags: usize) -> bool {
+    flags & IF_BIT != 0
+}
+
+#[inline]
+pub fn local_irq_enable() {
+    unsafe { asm!("sti") };
+}
+
+#[inline]
+pub fn local_irq_disable() {
+    unsafe { asm!("cli") };
+}
+
+#[inline]
+pub fn irqs_enabled() -> bool {
+    let flags: usize;
+    unsafe { asm!("pushf; pop {}", out(reg) flags) };
+    flags & IF_BIT != 0
+}
+
+/// Returns the base address of the per-CPU data, which is saved at `gs:[0]`.
+#[cfg(feature = "percpu")]
+#[inline]
+pub fn local_percpu_base() -> usize {
+    let base: usize;
+    unsafe { asm!("mov {}, gs:[0]", out(reg) base) };
+    base
+}
+
+/// Increments the preemption count in the per-CPU data.
+///
+/// It is a single instruction relative to `GS`, which can not be interrupted
+/// or migrated to another CPU in the middle, so local IRQs are kept enabled.
+#[cfg(feature = "preempt-count")]
+#[inline]
+pub fn preempt_count_inc() {
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        asm!(
+            "inc qword ptr gs:[{off}]",
+            off = const PREEMPT_COUNT_OFFSET,
+            options(nostack)
+        )
+    };
+    #[cfg(target_arch = "x86")]
+    unsafe {
+        asm!(
+            "inc dword ptr gs:[{off}]",
+            off = const PREEMPT_COUNT_OFFSET,
+            options(nostack)
+        )
+    };
+}
+
+/// Decrements the preemption count in the per-CPU data with a single
+/// instruction, and returns whether it drops to zero.
+#[cfg(feature = "preempt-count")]
+#[inline]
+pub fn preempt_count_dec() -> bool {
+    let zero: u8;
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        asm!(
+            "dec qword ptr gs:[{off}]",
+            "setz {zero}",
+            off = const PREEMPT_COUNT_OFFSET,
+            zero = out(reg_byte) zero,
+            options(nostack)
+        )
+    };
+    #[cfg(target_arch = "x86")]
+    unsafe {
+        asm!(
+            "dec dword ptr gs:[{off}]",
+            "setz {zero}",
+            off = const PREEMPT_COUNT_OFFSET,
+            zero = out(reg_byte) zero,
+            options(nostack)
+        )
+    };
+    zero != 0
+}
+
+/// Reads whether a reschedule is pending in the per-CPU data with a single
+/// instruction.
+#[cfg(feature = "preempt-count")]
+#[inline]
+pub fn need_resched() -> bool {
+    let need: u8;
+    unsafe {
+        asm!(
+            "mov {need}, byte ptr gs:[{off}]",
+            off = const NEED_RESCHED_OFFSET,
+            need = out(reg_byte) need,
+            options(nostack, readonly)
+        )
+    };
+    need != 0
+}
+
+/// Reads the timestamp counter (TSC). Only the lower 32 bits are kept on
+/// 32-bit x86.
+#[cfg(any(feature = "irqsoff-trace", feature = "preemptoff-trace"))]
+#[inline]
+pub fn read_timestamp() -> usize {
+    let (lo, hi): (u32, u32);
+    unsafe { asm!("rdtsc", out("eax") lo, out("edx") hi, options(nomem, nostack)) };
+    (((hi as u64) << 32) | lo as u64) as usize
+}
+
+/// The TSC frequency is unknown, which must be set by the kernel.
+#[cfg(feature = "watchdog")]
+#[inline]
+pub fn default_timestamp_frequency() -> usize {
+    0
+}
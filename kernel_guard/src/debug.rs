@@ -0,0 +1,267 @@
+//! Debugging checks for the built-in guards, enabled by the feature
+//! `debug-guards`.
+
+use core::panic::Location;
+use core::sync::atomic::{AtomicPtr, AtomicU8, AtomicUsize, Ordering};
+
+use crate::guard::{GuardKind, TrackedGuard};
+use crate::percpu::with_local;
+
+/// The maximum nesting depth of each kind of guards on a CPU.
+///
+/// Exceeding it usually means some guards are leaked (e.g., forgotten).
+pub const MAX_DEPTH: usize = 255;
+
+/// Capacity of the per-CPU stack of active guards. Guards acquired when the
+/// stack is full are not checked for the drop order.
+const STACK_SIZE: usize = 16;
+
+/// Slot of the guards that are not in the stack.
+pub(crate) const UNTRACKED: u8 = u8::MAX;
+
+/// Slot of the states rebuilt from raw values (e.g., by
+/// [`IrqState::from_raw`](crate::IrqState::from_raw)), which is lost. They are
+/// assumed to be released in order.
+pub(crate) const UNKNOWN: u8 = u8::MAX - 1;
+
+/// Kind of a free entry in the stack.
+const FREE: u8 = u8::MAX;
+
+/// A handler called when a guard is released before another guard of the same
+/// kind acquired after it.
+///
+/// The arguments are the guard kind, the acquisition site of the released
+/// guard, and the acquisition site of the guard that should be released first.
+pub type LifoViolationHandler =
+    fn(GuardKind, &'static Location<'static>, &'static Location<'static>);
+
+static LIFO_VIOLATION_HANDLER: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Registers the handler for guards released out of order, replacing the
+/// previously registered one.
+///
+/// Before any handler is registered, it panics with both acquisition sites.
+///
+/// # Examples
+///
+/// ```
+/// use core::sync::atomic::{AtomicBool, Ordering};
+/// use kernel_guard::{GuardKind, IrqSave};
+///
+/// static VIOLATED: AtomicBool = AtomicBool::new(false);
+///
+/// kernel_guard::set_lifo_violation_handler(|kind, released, expected| {
+///     assert_eq!(kind, GuardKind::Irq);
+///     assert_eq!(released.file(), file!());
+///     assert!(released.line() < expected.line());
+///     VIOLATED.store(true, Ordering::Relaxed);
+/// });
+///
+/// let outer = IrqSave::new();
+/// let inner = IrqSave::new();
+/// drop(outer); // IRQs are enabled while `inner` is still held
+/// assert!(VIOLATED.load(Ordering::Relaxed));
+/// drop(inner);
+/// ```
+///
+/// ```should_panic
+/// use kernel_guard::IrqSave;
+///
+/// let outer = IrqSave::new();
+/// let inner = IrqSave::new();
+/// drop(outer);
+/// ```
+///
+/// A state rebuilt from its raw value is released like the original one:
+///
+/// ```
+/// use kernel_guard::{BaseGuard, DisableIrqs, IrqSave, IrqState};
+///
+/// let outer = IrqSave::new();
+/// let state = DisableIrqs::acquire();
+/// DisableIrqs::release(IrqState::from_raw(state.into_raw()));
+/// drop(outer); // no LIFO violation
+/// ```
+pub fn set_lifo_violation_handler(handler: LifoViolationHandler) {
+    LIFO_VIOLATION_HANDLER.store(handler as *mut (), Ordering::Release);
+}
+
+pub(crate) fn lifo_violation(
+    kind: GuardKind,
+    released: &'static Location<'static>,
+    expected: &'static Location<'static>,
+) {
+    let handler = LIFO_VIOLATION_HANDLER.load(Ordering::Acquire);
+    if handler.is_null() {
+        panic!(
+            "{kind:?} guard acquired at {released} is released before the one acquired at {expected}"
+        );
+    }
+    // SAFETY: the pointer is either null or comes from a `LifoViolationHandler`.
+    let handler: LifoViolationHandler = unsafe { core::mem::transmute(handler) };
+    handler(kind, released, expected);
+}
+
+struct StackEntry {
+    kind: AtomicU8,
+    location: AtomicPtr<Location<'static>>,
+}
+
+/// Per-CPU data for the debugging checks.
+pub(crate) struct DebugData {
+    /// Stack of the active guards, which may contain released (free) entries
+    /// below the top.
+    stack: [StackEntry; STACK_SIZE],
+    stack_len: AtomicUsize,
+}
+
+impl DebugData {
+    pub(crate) const fn new() -> Self {
+        Self {
+            stack: [const {
+                StackEntry {
+                    kind: AtomicU8::new(FREE),
+                    location: AtomicPtr::new(core::ptr::null_mut()),
+                }
+            }; STACK_SIZE],
+            stack_len: AtomicUsize::new(0),
+        }
+    }
+}
+
+fn location_of(entry: &StackEntry) -> &'static Location<'static> {
+    // SAFETY: the pointer of a used entry comes from a `&'static Location`.
+    unsafe { &*entry.location.load(Ordering::Relaxed) }
+}
+
+/// Returns the nesting depth of the guards of the same kind as `G` on the
+/// current CPU.
+///
+/// # Examples
+///
+/// ```
+/// use kernel_guard::{depth, IrqSave, NoPreempt, NoPreemptIrqSave};
+///
+/// let _a = IrqSave::new();
+/// let _b = NoPreemptIrqSave::new();
+/// assert_eq!(depth::<IrqSave>(), 2);
+/// assert_eq!(depth::<NoPreempt>(), 1);
+/// ```
+///
+/// Releasing a guard without a matching acquire panics:
+///
+/// ```should_panic
+/// use kernel_guard::{BaseGuard, IrqSave};
+///
+/// let state = IrqSave::acquire();
+/// IrqSave::release(state);
+/// IrqSave::release(state);
+/// ```
+pub fn depth<G: TrackedGuard>() -> usize {
+    with_local(|data| data.depths[G::KIND as usize].load(Ordering::Relaxed))
+}
+
+/// Calls `f` with the kind and acquisition site of each built-in guard held on
+/// the current CPU, from the outermost to the innermost.
+///
+/// It is intended to be called by the panic handler. Only the first 16 guards
+/// are recorded, the ones nested deeper are not listed. It is also available
+/// with the feature `track-location`, which implies `debug-guards`.
+///
+/// # Examples
+///
+/// ```
+/// use kernel_guard::{GuardKind, NoPreemptIrqSave};
+///
+/// let _guard = NoPreemptIrqSave::new();
+/// let mut held = Vec::new();
+/// kernel_guard::for_each_held_guard(|kind, location| held.push((kind, location.line())));
+/// assert_eq!(held, [(GuardKind::Preempt, line!() - 3), (GuardKind::Irq, line!() - 3)]);
+/// ```
+pub fn for_each_held_guard(mut f: impl FnMut(GuardKind, &'static Location<'static>)) {
+    // Copy the entries first, so that `f` is not called with IRQs disabled.
+    let mut held = [None; STACK_SIZE];
+    with_local(|data| {
+        let data = &data.debug;
+        let len = data.stack_len.load(Ordering::Relaxed);
+        for (entry, held) in data.stack[..len].iter().zip(&mut held) {
+            let kind = entry.kind.load(Ordering::Relaxed);
+            if kind != FREE {
+                *held = Some((GuardKind::ALL[kind as usize], location_of(entry)));
+            }
+        }
+    });
+    for (kind, location) in held.into_iter().flatten() {
+        f(kind, location);
+    }
+}
+
+/// Records a guard of `kind` acquired at `location`, which makes the nesting
+/// depth `depth`, and returns its slot in the stack.
+#[inline]
+pub(crate) fn on_acquire(
+    data: &DebugData,
+    kind: GuardKind,
+    depth: usize,
+    location: &'static Location<'static>,
+) -> u8 {
+    assert!(depth <= MAX_DEPTH, "{kind:?} guard nesting overflow");
+    let len = data.stack_len.load(Ordering::Relaxed);
+    if len < STACK_SIZE {
+        let entry = &data.stack[len];
+        entry.kind.store(kind as u8, Ordering::Relaxed);
+        entry
+            .location
+            .store(location as *const _ as *mut _, Ordering::Relaxed);
+        data.stack_len.store(len + 1, Ordering::Relaxed);
+        len as u8
+    } else {
+        UNTRACKED
+    }
+}
+
+/// Records a guard of `kind` in `slot` released at the nesting depth `depth`,
+/// and returns the acquisition sites of the released guard and the guard of
+/// the same kind acquired after it that is still active, if any.
+#[inline]
+pub(crate) fn on_release(
+    data: &DebugData,
+    kind: GuardKind,
+    depth: usize,
+    slot: u8,
+) -> Option<(&'static Location<'static>, &'static Location<'static>)> {
+    assert!(
+        depth > 0,
+        "{kind:?} guard released without a matching acquire"
+    );
+    let mut len = data.stack_len.load(Ordering::Relaxed);
+    let slot = if slot == UNKNOWN {
+        // The innermost guard of `kind` is released. If some guards of `kind`
+        // are not in the stack, it is one of them.
+        let mut live = data.stack[..len]
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.kind.load(Ordering::Relaxed) == kind as u8);
+        match live.next_back() {
+            Some((slot, _)) if live.count() + 1 == depth => slot,
+            _ => return None,
+        }
+    } else {
+        slot as usize
+    };
+    if slot >= len || data.stack[slot].kind.load(Ordering::Relaxed) != kind as u8 {
+        return None;
+    }
+    let violation = data.stack[slot + 1..len]
+        .iter()
+        .rev()
+        .find(|entry| entry.kind.load(Ordering::Relaxed) == kind as u8)
+        .map(|expected| (location_of(&data.stack[slot]), location_of(expected)));
+
+    data.stack[slot].kind.store(FREE, Ordering::Relaxed);
+    while len > 0 && data.stack[len - 1].kind.load(Ordering::Relaxed) == FREE {
+        len -= 1;
+    }
+    data.stack_len.store(len, Ordering::Relaxed);
+    violation
+}
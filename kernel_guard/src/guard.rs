@@ -0,0 +1,580 @@
+//! The generic RAII guard and the built-in guard operations.
+
+use core::marker::PhantomData;
+#[cfg(feature = "track-location")]
+use core::panic::Location;
+
+use crate::irq::IrqState;
+#[cfg(any(feature = "preempt-count", feature = "preempt-hooks"))]
+use crate::preempt::enable_preempt_no_resched;
+use crate::preempt::{disable_preempt, enable_preempt, preempt_disabled};
+use crate::{BaseGuard, NotSendSync};
+
+/// A RAII guard that creates a critical section with the operations of `G`.
+///
+/// It calls [`BaseGuard::acquire`] when created, and [`BaseGuard::release`]
+/// with the saved state when dropped. All the guards in this crate are aliases
+/// of it, and any type that implements [`BaseGuard`] gets the same guard API.
+///
+/// [`Guard`] itself also implements [`BaseGuard`] by forwarding to `G`, so it
+/// can be used wherever a [`BaseGuard`] is expected.
+///
+/// # Examples
+///
+/// ```
+/// use kernel_guard::{BaseGuard, DisableIrqs, Guard};
+///
+/// /// Masks the interrupts of some board-specific controller.
+/// struct BoardIrqMask;
+///
+/// impl BaseGuard for BoardIrqMask {
+///     type State = u32;
+///     fn acquire() -> Self::State {
+///         // Save and set the mask here
+///         0
+///     }
+///     fn release(state: Self::State) {
+///         // Restore the mask here
+///     }
+/// }
+///
+/// let guard = Guard::<BoardIrqMask>::new();
+/// /* The critical section starts here */
+/// drop(guard);
+///
+/// // Composed with the built-in operations, acquired from left to right.
+/// let guard = Guard::<(DisableIrqs, BoardIrqMask)>::new();
+/// assert!(!kernel_guard::irqs_enabled());
+/// drop(guard);
+/// assert!(kernel_guard::irqs_enabled());
+/// ```
+pub struct Guard<G: BaseGuard> {
+    state: G::State,
+    #[cfg(feature = "track-location")]
+    location: &'static Location<'static>,
+    _marker: PhantomData<(G, NotSendSync)>,
+}
+
+impl<G: BaseGuard> Guard<G> {
+    /// Creates a new guard, which enters the critical section.
+    #[inline]
+    #[track_caller]
+    pub fn new() -> Self {
+        Self::from_state(G::acquire())
+    }
+
+    /// Creates a guard that owns the already acquired `state`, and records the
+    /// caller as its acquisition site.
+    #[inline]
+    #[track_caller]
+    fn from_state(state: G::State) -> Self {
+        Self {
+            state,
+            #[cfg(feature = "track-location")]
+            location: Location::caller(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Turns the guard into another kind of guard that owns the state returned
+    /// by `f`, keeping the acquisition site.
+    #[inline]
+    fn map_state<H: BaseGuard>(self, f: impl FnOnce(G::State) -> H::State) -> Guard<H> {
+        #[cfg(feature = "track-location")]
+        let location = self.location;
+        Guard {
+            state: f(self.into_state()),
+            #[cfg(feature = "track-location")]
+            location,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns where the guard was created, i.e., the caller of
+    /// [`Guard::new`] or the other constructors.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kernel_guard::IrqSave;
+    ///
+    /// let guard = IrqSave::new();
+    /// assert_eq!(guard.location().file(), file!());
+    /// assert_eq!(guard.location().line(), line!() - 2);
+    /// ```
+    #[cfg(feature = "track-location")]
+    #[inline]
+    pub fn location(&self) -> &'static Location<'static> {
+        self.location
+    }
+
+    /// Forgets the guard without releasing it, and returns its state.
+    #[inline]
+    fn into_state(self) -> G::State {
+        let state = self.state;
+        core::mem::forget(self);
+        state
+    }
+}
+
+impl<G: BaseGuard> Guard<G> {
+    /// Forgets the guard without releasing it, and returns the saved state.
+    ///
+    /// It is used to hand the critical section over to another context that
+    /// can not be expressed by scoping, e.g., a lock taken by the old task
+    /// before a context switch, and released by the new task after it. The
+    /// state must be turned back into a guard by [`Guard::from_raw`] later.
+    ///
+    /// # Safety
+    ///
+    /// The saved state is per-CPU. It must be passed to [`Guard::from_raw`]
+    /// exactly once, on the same CPU where the guard was created.
+    ///
+    /// In debug builds with the feature `percpu`, the states of the built-in
+    /// guard operations ([`DisableIrqs`] and [`DisablePreempt`]) record the
+    /// CPU where they are saved, and releasing them on another CPU panics.
+    /// Otherwise, nothing is checked.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kernel_guard::{irqs_enabled, IrqSave};
+    ///
+    /// let guard = IrqSave::new();
+    /// let state = unsafe { guard.into_raw() };
+    /// // switch_to(next_task);
+    /// assert!(!irqs_enabled());
+    /// drop(unsafe { IrqSave::from_raw(state) });
+    /// assert!(irqs_enabled());
+    /// ```
+    #[inline]
+    pub unsafe fn into_raw(self) -> G::State {
+        self.into_state()
+    }
+
+    /// Reconstitutes a guard from the state returned by [`Guard::into_raw`].
+    ///
+    /// # Safety
+    ///
+    /// `state` must come from [`Guard::into_raw`] of the same kind of guard on
+    /// the current CPU, and must not be used again. See [`Guard::into_raw`] for
+    /// what is checked in debug builds.
+    ///
+    /// The returned guard is recorded as created by the caller, since the
+    /// original acquisition site is not saved in `state`.
+    #[inline]
+    #[track_caller]
+    pub unsafe fn from_raw(state: G::State) -> Self {
+        Self::from_state(state)
+    }
+
+    /// Runs `f` inside a critical section created by this kind of guard.
+    ///
+    /// It is the same as [`with::<G, R>`](with).
+    #[inline]
+    #[track_caller]
+    pub fn run<R>(f: impl FnOnce() -> R) -> R {
+        with::<G, R>(f)
+    }
+
+    /// Temporarily leaves the critical section to run `f`, and enters it
+    /// again after `f` returns (or panics and unwinds).
+    ///
+    /// It releases the guard with the saved state, so only what was enabled
+    /// when the guard was created is re-enabled during `f`. E.g., `f` runs
+    /// with IRQs still disabled if the [`IrqSave`](crate::IrqSave) guard is
+    /// nested in another one. It is useful to bound the latency of a long
+    /// critical section without dropping and re-creating the guard.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kernel_guard::{irqs_enabled, IrqSave};
+    ///
+    /// let mut guard = IrqSave::new();
+    /// for _ in 0..3 {
+    ///     // Do some work with IRQs disabled
+    ///     assert!(!irqs_enabled());
+    ///     // Let pending IRQs be serviced
+    ///     guard.unlocked(|| assert!(irqs_enabled()));
+    /// }
+    ///
+    /// let mut inner = IrqSave::new();
+    /// inner.unlocked(|| assert!(!irqs_enabled()));
+    /// ```
+    #[track_caller]
+    pub fn unlocked<R>(&mut self, f: impl FnOnce() -> R) -> R {
+        /// Re-enters the critical section if `f` unwinds.
+        struct Reacquire<'a, G: BaseGuard>(&'a mut G::State);
+
+        impl<G: BaseGuard> Drop for Reacquire<'_, G> {
+            fn drop(&mut self) {
+                *self.0 = G::acquire();
+            }
+        }
+
+        G::release(self.state);
+        let reacquire = Reacquire::<G>(&mut self.state);
+        let ret = f();
+        core::mem::forget(reacquire);
+        self.state = G::acquire();
+        ret
+    }
+}
+
+impl<G: BaseGuard> Drop for Guard<G> {
+    #[inline]
+    fn drop(&mut self) {
+        G::release(self.state)
+    }
+}
+
+impl<G: BaseGuard> Default for Guard<G> {
+    #[track_caller]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<G: BaseGuard> BaseGuard for Guard<G> {
+    type State = G::State;
+
+    #[inline]
+    #[track_caller]
+    fn acquire() -> Self::State {
+        G::acquire()
+    }
+
+    #[inline]
+    fn release(state: Self::State) {
+        G::release(state)
+    }
+}
+
+/// Runs `f` inside a critical section created by the guard operations `G`.
+///
+/// `G` is acquired before calling `f`, and released after `f` returns. It is
+/// also released if `f` panics and the panic unwinds.
+///
+/// # Examples
+///
+/// ```
+/// use kernel_guard::{irqs_enabled, IrqSave};
+///
+/// let enabled = kernel_guard::with::<IrqSave, _>(irqs_enabled);
+/// assert!(!enabled);
+/// assert!(irqs_enabled());
+///
+/// // The same as above.
+/// assert!(!IrqSave::run(irqs_enabled));
+///
+/// // Released on unwinding.
+/// let res = std::panic::catch_unwind(|| IrqSave::run(|| panic!()));
+/// assert!(res.is_err());
+/// assert!(irqs_enabled());
+/// ```
+#[inline]
+#[track_caller]
+pub fn with<G: BaseGuard, R>(f: impl FnOnce() -> R) -> R {
+    let _guard = Guard::<G>::new();
+    f()
+}
+
+macro_rules! impl_tuple_guard {
+    ($($ty:ident $idx:tt),+; $($rty:ident $ridx:tt),+) => {
+        /// Composes the guard operations: they are acquired from left to right,
+        /// and released from right to left.
+        impl<$($ty: BaseGuard),+> BaseGuard for ($($ty,)+) {
+            type State = ($($ty::State,)+);
+
+            #[inline]
+            #[track_caller]
+            fn acquire() -> Self::State {
+                ($($ty::acquire(),)+)
+            }
+
+            #[inline]
+            fn release(state: Self::State) {
+                $($rty::release(state.$ridx);)+
+            }
+        }
+    };
+}
+
+impl_tuple_guard!(A 0, B 1; B 1, A 0);
+impl_tuple_guard!(A 0, B 1, C 2; C 2, B 1, A 0);
+impl_tuple_guard!(A 0, B 1, C 2, D 3; D 3, C 2, B 1, A 0);
+impl_tuple_guard!(A 0, B 1, C 2, D 3, E 4; E 4, D 3, C 2, B 1, A 0);
+impl_tuple_guard!(A 0, B 1, C 2, D 3, E 4, F 5; F 5, E 4, D 3, C 2, B 1, A 0);
+impl_tuple_guard!(A 0, B 1, C 2, D 3, E 4, F 5, G 6; G 6, F 5, E 4, D 3, C 2, B 1, A 0);
+impl_tuple_guard!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7; H 7, G 6, F 5, E 4, D 3, C 2, B 1, A 0);
+
+/// Kinds of the built-in guard operations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum GuardKind {
+    /// Disables local IRQs, i.e., [`DisableIrqs`].
+    Irq,
+    /// Disables kernel preemption, i.e., [`DisablePreempt`].
+    Preempt,
+}
+
+impl GuardKind {
+    /// Number of the guard kinds.
+    #[cfg(any(
+        feature = "debug-guards",
+        feature = "irqsoff-trace",
+        feature = "preemptoff-trace",
+        feature = "guard-stats"
+    ))]
+    pub(crate) const COUNT: usize = 2;
+
+    /// All the guard kinds, indexed by their discriminants.
+    #[cfg(feature = "debug-guards")]
+    pub(crate) const ALL: [Self; Self::COUNT] = [Self::Irq, Self::Preempt];
+}
+
+/// Guard operations of a built-in [`GuardKind`], which can be tracked by the
+/// debugging features.
+pub trait TrackedGuard: BaseGuard {
+    /// The kind of the guard operations.
+    const KIND: GuardKind;
+}
+
+/// Guard operations that disable/enable local IRQs around the critical
+/// section. Used by [`IrqSave`](crate::IrqSave).
+pub enum DisableIrqs {}
+
+impl BaseGuard for DisableIrqs {
+    type State = IrqState;
+
+    #[inline]
+    #[track_caller]
+    fn acquire() -> Self::State {
+        #[allow(unused_mut)]
+        let mut state = IrqState::save_and_disable();
+        #[cfg(any(
+            feature = "debug-guards",
+            feature = "irqsoff-trace",
+            feature = "preemptoff-trace",
+            feature = "guard-stats"
+        ))]
+        let _slot = crate::percpu::on_acquire(GuardKind::Irq, state.is_enabled());
+        #[cfg(feature = "debug-guards")]
+        {
+            state.slot = _slot;
+        }
+        #[cfg(feature = "observer")]
+        crate::observer::on_acquire(GuardKind::Irq, state.into_raw());
+        state
+    }
+
+    #[inline]
+    fn release(state: Self::State) {
+        #[cfg(all(debug_assertions, feature = "percpu"))]
+        crate::percpu::check_cpu(state.cpu);
+        #[cfg(feature = "observer")]
+        crate::observer::on_release(GuardKind::Irq, state.into_raw());
+        #[cfg(feature = "debug-guards")]
+        let _slot = state.slot;
+        #[cfg(not(feature = "debug-guards"))]
+        let _slot = 0;
+        #[cfg(any(
+            feature = "debug-guards",
+            feature = "irqsoff-trace",
+            feature = "preemptoff-trace",
+            feature = "guard-stats"
+        ))]
+        crate::percpu::on_release(GuardKind::Irq, _slot, state.is_enabled());
+        // restore IRQ states
+        state.restore();
+    }
+}
+
+impl TrackedGuard for DisableIrqs {
+    const KIND: GuardKind = GuardKind::Irq;
+}
+
+/// The state saved when kernel preemption is disabled by [`DisablePreempt`].
+///
+/// It only carries some debugging information.
+#[derive(Clone, Copy, Debug)]
+pub struct PreemptState {
+    /// Slot in the per-CPU stack of active guards.
+    #[cfg(feature = "debug-guards")]
+    slot: u8,
+    /// The CPU where the state is saved.
+    #[cfg(all(debug_assertions, feature = "percpu"))]
+    cpu: usize,
+}
+
+/// Guard operations that disable/enable kernel preemption around the critical
+/// section. Used by [`NoPreempt`](crate::NoPreempt).
+pub enum DisablePreempt {}
+
+impl BaseGuard for DisablePreempt {
+    type State = PreemptState;
+
+    #[inline]
+    #[track_caller]
+    fn acquire() -> Self::State {
+        disable_preempt();
+        Self::on_acquire()
+    }
+
+    #[inline]
+    fn release(state: Self::State) {
+        Self::on_release(state);
+        enable_preempt();
+    }
+}
+
+impl DisablePreempt {
+    /// Bookkeeping of the debugging features after preemption is disabled.
+    #[inline]
+    #[track_caller]
+    fn on_acquire() -> PreemptState {
+        #[cfg(any(
+            feature = "debug-guards",
+            feature = "irqsoff-trace",
+            feature = "preemptoff-trace",
+            feature = "guard-stats"
+        ))]
+        let _slot = crate::percpu::on_acquire(GuardKind::Preempt, false);
+        let state = PreemptState {
+            #[cfg(feature = "debug-guards")]
+            slot: _slot,
+            #[cfg(all(debug_assertions, feature = "percpu"))]
+            cpu: crate::percpu::local_cpu_id(),
+        };
+        #[cfg(feature = "observer")]
+        crate::observer::on_acquire(GuardKind::Preempt, 0);
+        state
+    }
+
+    /// Bookkeeping of the debugging features before preemption is enabled.
+    #[inline]
+    fn on_release(_state: PreemptState) {
+        #[cfg(all(debug_assertions, feature = "percpu"))]
+        crate::percpu::check_cpu(_state.cpu);
+        #[cfg(feature = "observer")]
+        crate::observer::on_release(GuardKind::Preempt, 0);
+        #[cfg(feature = "debug-guards")]
+        let _slot = _state.slot;
+        #[cfg(not(feature = "debug-guards"))]
+        let _slot = 0;
+        #[cfg(any(
+            feature = "debug-guards",
+            feature = "irqsoff-trace",
+            feature = "preemptoff-trace",
+            feature = "guard-stats"
+        ))]
+        crate::percpu::on_release(GuardKind::Preempt, _slot, false);
+    }
+}
+
+impl TrackedGuard for DisablePreempt {
+    const KIND: GuardKind = GuardKind::Preempt;
+}
+
+impl<G: TrackedGuard> TrackedGuard for Guard<G> {
+    const KIND: GuardKind = G::KIND;
+}
+
+/// Guard operations that only check that local IRQs are already disabled,
+/// without changing the IRQ state. Used by
+/// [`AssertIrqsDisabled`](crate::AssertIrqsDisabled).
+///
+/// It panics if IRQs are enabled in debug builds, and does nothing in release
+/// builds.
+pub enum CheckIrqsDisabled {}
+
+impl BaseGuard for CheckIrqsDisabled {
+    type State = ();
+
+    #[inline]
+    fn acquire() -> Self::State {
+        debug_assert!(!crate::irqs_enabled(), "local IRQs are not disabled");
+    }
+
+    #[inline]
+    fn release(_state: Self::State) {}
+}
+
+/// Guard operations that only check that kernel preemption is already
+/// disabled, without changing it. Used by
+/// [`AssertPreemptDisabled`](crate::AssertPreemptDisabled).
+///
+/// It panics if preemption is enabled in debug builds, and does nothing in
+/// release builds. Preemption is also considered disabled if local IRQs are
+/// disabled. Without the feature `preempt-count`, the preemption state is
+/// unknown and the check always passes.
+pub enum CheckPreemptDisabled {}
+
+impl BaseGuard for CheckPreemptDisabled {
+    type State = ();
+
+    #[inline]
+    fn acquire() -> Self::State {
+        debug_assert!(preempt_disabled(), "kernel preemption is not disabled");
+    }
+
+    #[inline]
+    fn release(_state: Self::State) {}
+}
+
+impl Guard<DisablePreempt> {
+    /// Releases the guard like dropping it, but never reschedules even if a
+    /// reschedule is pending.
+    ///
+    /// It is used in the code paths that must not reschedule, such as the
+    /// scheduler itself. The built-in reschedule hook is not called, and
+    /// preemption is enabled by the `enable_preempt_no_resched` runtime hook
+    /// instead of `enable_preempt`. It is only available with the feature
+    /// `preempt-count` or `preempt-hooks`, since
+    /// [`KernelGuardIf`](crate::KernelGuardIf) only knows `enable_preempt`.
+    #[cfg(any(feature = "preempt-count", feature = "preempt-hooks"))]
+    pub fn release_no_resched(self) {
+        DisablePreempt::on_release(self.into_state());
+        enable_preempt_no_resched();
+    }
+
+    /// Upgrades to a [`NoPreemptIrqSave`](crate::NoPreemptIrqSave) guard by
+    /// saving and disabling local IRQs, while keeping preemption disabled.
+    ///
+    /// The result is the same as creating the guard directly: dropping it
+    /// restores the IRQ state saved here, and then enables preemption.
+    /// It keeps the acquisition site of the original guard.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use kernel_guard::{irqs_enabled, NoPreempt};
+    ///
+    /// let guard = NoPreempt::new();
+    /// let guard = guard.upgrade();
+    /// assert!(!irqs_enabled());
+    /// let guard = guard.downgrade();
+    /// assert!(irqs_enabled());
+    /// drop(guard);
+    /// ```
+    #[track_caller]
+    pub fn upgrade(self) -> Guard<(DisablePreempt, DisableIrqs)> {
+        let irq_state = DisableIrqs::acquire();
+        self.map_state(|preempt_state| (preempt_state, irq_state))
+    }
+}
+
+impl Guard<(DisablePreempt, DisableIrqs)> {
+    /// Downgrades to a [`NoPreempt`](crate::NoPreempt) guard by restoring
+    /// local IRQs to the saved state, while keeping preemption disabled.
+    ///
+    /// Dropping the returned guard enables preemption exactly as dropping the
+    /// original guard would, and it keeps the acquisition site of the original
+    /// guard.
+    pub fn downgrade(self) -> Guard<DisablePreempt> {
+        self.map_state(|(preempt_state, irq_state)| {
+            DisableIrqs::release(irq_state);
+            preempt_state
+        })
+    }
+}
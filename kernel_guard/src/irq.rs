@@ -0,0 +1,143 @@
+//! Raw operations on the local IRQ state.
+//!
+//! On hosted targets (not `target_os = "none"`), IRQs cannot be disabled in
+//! user mode, so only an emulated IRQ state (of each thread with the feature
+//! `std`) is changed by these functions.
+
+use core::fmt;
+
+use crate::arch;
+
+/// The local IRQ state saved when entering a critical section.
+///
+/// The raw value is architecture-specific: the `IF` bit of `RFLAGS` on x86,
+/// the `SIE` bit of `sstatus` on RISC-V, and the whole `DAIF` on AArch64. Use
+/// [`IrqState::is_enabled`] to inspect it in a portable way.
+#[derive(Clone, Copy)]
+pub struct IrqState {
+    raw: usize,
+    /// Slot in the per-CPU stack of active guards.
+    #[cfg(feature = "debug-guards")]
+    pub(crate) slot: u8,
+    /// The CPU where the state is saved.
+    #[cfg(all(debug_assertions, feature = "percpu"))]
+    pub(crate) cpu: usize,
+}
+
+impl IrqState {
+    /// Creates an [`IrqState`] from the raw architecture-specific value, which
+    /// is usually obtained by [`IrqState::into_raw`].
+    #[inline]
+    pub const fn from_raw(raw: usize) -> Self {
+        Self {
+            raw,
+            #[cfg(feature = "debug-guards")]
+            slot: crate::debug::UNKNOWN,
+            #[cfg(all(debug_assertions, feature = "percpu"))]
+            cpu: crate::percpu::UNKNOWN_CPU,
+        }
+    }
+
+    /// Returns the raw architecture-specific value.
+    #[inline]
+    pub const fn into_raw(self) -> usize {
+        self.raw
+    }
+
+    /// Whether local IRQs were enabled when the state was saved.
+    #[inline]
+    pub const fn is_enabled(self) -> bool {
+        arch::irq_state_enabled(self.raw)
+    }
+
+    /// Saves the current state and disables local IRQs.
+    #[inline]
+    pub(crate) fn save_and_disable() -> Self {
+        #[allow(unused_mut)]
+        let mut state = Self::from_raw(arch::local_irq_save_and_disable());
+        #[cfg(all(debug_assertions, feature = "percpu"))]
+        {
+            state.cpu = crate::percpu::local_cpu_id();
+        }
+        state
+    }
+
+    /// Restores local IRQs to this state.
+    #[inline]
+    pub(crate) fn restore(self) {
+        arch::local_irq_restore(self.raw)
+    }
+}
+
+impl PartialEq for IrqState {
+    fn eq(&self, other: &Self) -> bool {
+        self.raw == other.raw
+    }
+}
+
+impl Eq for IrqState {}
+
+impl fmt::Debug for IrqState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        struct Bits(usize);
+
+        impl fmt::Debug for Bits {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                let mut first = true;
+                for &(name, bit) in arch::IRQ_STATE_BITS {
+                    if self.0 & bit != 0 {
+                        if !first {
+                            f.write_str(" | ")?;
+                        }
+                        f.write_str(name)?;
+                        first = false;
+                    }
+                }
+                if first {
+                    f.write_str("(empty)")?;
+                }
+                Ok(())
+            }
+        }
+
+        f.debug_struct("IrqState")
+            .field("enabled", &self.is_enabled())
+            .field("bits", &Bits(self.raw))
+            .finish()
+    }
+}
+
+/// Whether local IRQs are enabled on the current CPU.
+///
+/// # Examples
+///
+/// ```
+/// use kernel_guard::{irqs_enabled, local_irq_disable, local_irq_enable};
+///
+/// local_irq_disable();
+/// assert!(!irqs_enabled());
+/// local_irq_enable();
+/// assert!(irqs_enabled());
+/// ```
+#[inline]
+pub fn irqs_enabled() -> bool {
+    arch::irqs_enabled()
+}
+
+/// Enables local IRQs on the current CPU.
+///
+/// It is usually better to use the guards, which restore the previous state
+/// instead of enabling IRQs unconditionally.
+#[inline]
+pub fn local_irq_enable() {
+    arch::local_irq_enable()
+}
+
+/// Disables local IRQs on the current CPU.
+///
+/// It is usually better to use the guards, which restore the previous state
+/// when the critical section is ended.
+#[inline]
+pub fn local_irq_disable() {
+    arch::local_irq_disable()
+}
@@ -13,14 +13,100 @@
 //!
 //! - [`NoOp`]: Does nothing around the critical section.
 //! - [`IrqSave`]: Disables/enables local IRQs around the critical section.
+//! - [`NoPreempt`]: Disables/enables kernel preemption around the critical
 //!   section.
+//! - [`NoPreemptIrqSave`]: Disables/enables both kernel preemption and local
+//!   IRQs around the critical section.
+//! - [`AssertIrqsDisabled`]/[`AssertPreemptDisabled`]: Only check that local
+//!   IRQs/kernel preemption are already disabled, in debug builds.
+//! - [`MaybeGuard`]/[`DynGuard`]: Decide what to disable at runtime.
+//!
+//! The guards that disable or check IRQs/preemption are aliases of the generic
+//! [`Guard`], which provides the RAII behavior for any type that implements
+//! [`BaseGuard`], while [`MaybeGuard`] and [`DynGuard`] wrap it with a runtime
+//! condition. Guard operations can be composed with tuples, e.g.,
+//! `Guard<(DisablePreempt, DisableIrqs)>` acquires them from left to right,
+//! and releases them from right to left.
+//!
+//! Instead of holding a guard, a closure can also be run inside the critical
+//! section by [`Guard::run`] or [`with`].
+//!
+//! Guards that disable IRQs or preemption can lend zero-sized tokens
+//! ([`IrqsDisabled`] and [`PreemptDisabled`]), which prove that the caller is
+//! inside such a critical section.
+//!
+//! For user-mode apps (not `target_os = "none"`), IRQs and preemption can
+//! not be disabled. The local IRQ state is only emulated (per thread with the
+//! feature `std`), and the [`KernelGuardIf`] is never called.
+//!
+//! The local IRQ state can also be queried or changed directly by
+//! [`irqs_enabled`], [`local_irq_enable`] and [`local_irq_disable`].
 //!
 //! # Crate features
 //!
+//! - `std`: Emulate the local IRQ state and the per-CPU data per thread on
+//!   hosted targets (not `target_os = "none"`). Otherwise, all threads share
+//!   one emulated CPU there. It has no effect on bare metal.
 //! - `preempt`: Use in the preemptive system. If this feature is enabled, you
-//!    need to implement the [`KernelGuardIf`] trait in other crates. Otherwise
-//!    the preemption enable/disable operations will be no-ops. This feature is
-//!    disabled by default.
+//!   need to implement the [`KernelGuardIf`] trait in other crates. Otherwise
+//!   the preemption enable/disable operations will be no-ops. This feature is
+//!   disabled by default.
+//! - `preempt-hooks`: Like `preempt`, but the preemption enable/disable
+//!   operations are registered at runtime by `set_preempt_hooks` instead of
+//!   being bound at link time through [`KernelGuardIf`]. It is useful when the
+//!   hooks need to be switched during boot. This feature implies `preempt`.
+//! - `percpu`: Keep some per-CPU data in this crate. The kernel must set up a
+//!   `PerCpuData` for each CPU (see its documentation).
+//! - `preempt-count`: Use the built-in per-CPU preemption count to implement
+//!   the preemption enable/disable operations, instead of [`KernelGuardIf`].
+//!   If `preempt-hooks` is also enabled, the runtime hooks are still called
+//!   along with updating the count. The current count can be queried by `preempt_count`,
+//!   `preemptible` and `in_atomic`. A reschedule can be requested by
+//!   `set_need_resched`, then the hook registered by `set_resched_hook` is
+//!   called when the outermost preemption-disabling guard is released. This
+//!   feature implies `preempt` and `percpu`.
+//! - `debug-guards`: Track the per-CPU nesting depth of each kind of built-in
+//!   guards, which can be queried by `depth`. It panics if the depth exceeds
+//!   `MAX_DEPTH` (usually caused by leaked guards), or a guard is released
+//!   without a matching acquire. It also checks that the guards of the same
+//!   kind are released in the reverse order of acquisition, and reports both
+//!   acquisition sites to the handler set by `set_lifo_violation_handler` (or
+//!   panics by default) if not. The guards held on the current CPU can be
+//!   listed by `for_each_held_guard`, e.g., in the panic handler. This feature
+//!   implies `percpu`.
+//! - `track-location`: Record where each [`Guard`] is created, which can be
+//!   queried by `Guard::location`. The guards held on the current CPU are
+//!   listed by `for_each_held_guard` of `debug-guards`. This feature implies
+//!   `debug-guards`.
+//! - `irqsoff-trace`: Measure how long local IRQs stay disabled by each
+//!   outermost [`IrqSave`] on each CPU, using the architecture-specific
+//!   timestamp source. The durations are recorded into log2 histograms, along
+//!   with where the guard of the longest one was created, and can be read by
+//!   `trace::snapshot`. This feature implies `percpu`.
+//! - `preemptoff-trace`: Like `irqsoff-trace`, but measure how long kernel
+//!   preemption stays disabled by each outermost guard that disables
+//!   preemption. Only the count and the longest duration with its call site
+//!   are recorded, which can be cleared by `trace::reset`. This feature
+//!   implies `percpu`.
+//! - `watchdog`: Check the outermost IRQs-off and preemption-off sections
+//!   against the budgets set by `set_irqsoff_budget` and
+//!   `set_preemptoff_budget`, and call the handler set by
+//!   `set_budget_handler` with the elapsed time and the acquisition site if a
+//!   section lasts too long. On x86 and RISC-V, the kernel must also provide
+//!   the timestamp frequency by `set_timestamp_frequency`. This feature
+//!   implies `irqsoff-trace` and `preemptoff-trace`.
+//! - `observer`: Call the `GuardObserver` registered by `set_guard_observer`
+//!   whenever a built-in guard that disables local IRQs or kernel preemption
+//!   is acquired or released, e.g., to implement tracing, statistics or lock
+//!   dependency checks outside this crate.
+//! - `static-observer`: Also let the kernel provide the observer at link time
+//!   by implementing `GuardObserverIf`, which is used on bare metal when no
+//!   observer is registered at runtime. This feature implies `observer`.
+//! - `guard-stats`: Count the built-in guards acquired on each CPU for each
+//!   kind, including the outermost ones and the maximum nesting depth, which
+//!   can be queried by `guard_stats` as a `GuardStats`. The counters are only
+//!   updated by the owning CPU, without atomic read-modify-write operations.
+//!   This feature implies `percpu`.
 //!
 //! # Examples
 //!
@@ -49,9 +135,70 @@
 //! ```
 
 #![no_std]
-#![feature(asm_const)]
+
+use core::marker::PhantomData;
 
 mod arch;
+mod guard;
+mod irq;
+mod maybe;
+mod preempt;
+mod token;
+
+#[cfg(feature = "debug-guards")]
+mod debug;
+#[cfg(feature = "observer")]
+mod observer;
+#[cfg(feature = "percpu")]
+mod percpu;
+#[cfg(feature = "guard-stats")]
+mod stats;
+#[cfg(any(feature = "irqsoff-trace", feature = "preemptoff-trace"))]
+pub mod trace;
+#[cfg(feature = "watchdog")]
+mod watchdog;
+
+#[cfg(feature = "debug-guards")]
+pub use self::debug::{
+    depth, for_each_held_guard, set_lifo_violation_handler, LifoViolationHandler, MAX_DEPTH,
+};
+pub use self::guard::{
+    with, CheckIrqsDisabled, CheckPreemptDisabled, DisableIrqs, DisablePreempt, Guard, GuardKind,
+    PreemptState, TrackedGuard,
+};
+pub use self::irq::{irqs_enabled, local_irq_disable, local_irq_enable, IrqState};
+pub use self::maybe::{DynGuard, MaybeGuard};
+#[cfg(feature = "static-observer")]
+pub use self::observer::GuardObserverIf;
+#[cfg(feature = "observer")]
+pub use self::observer::{set_guard_observer, GuardObserver};
+#[cfg(feature = "percpu")]
+pub use self::percpu::PerCpuData;
+#[cfg(feature = "preempt-count")]
+pub use self::preempt::{
+    clear_need_resched, in_atomic, need_resched, preempt_count, preemptible, set_need_resched,
+    set_resched_hook,
+};
+#[cfg(feature = "preempt-hooks")]
+pub use self::preempt::{set_preempt_hooks, PreemptHooks};
+#[cfg(feature = "guard-stats")]
+pub use self::stats::{guard_stats, guard_stats_of, GuardStats};
+pub use self::token::{IrqsDisabled, IrqsOff, PreemptDisabled, PreemptOff};
+#[cfg(feature = "watchdog")]
+pub use self::watchdog::{
+    set_budget_handler, set_irqsoff_budget, set_preemptoff_budget, set_timestamp_frequency,
+    BudgetHandler,
+};
+
+/// Low-level interfaces that must be implemented by the crate user.
+#[crate_interface::def_interface]
+pub trait KernelGuardIf {
+    /// How to enable kernel preemption.
+    fn enable_preempt();
+
+    /// How to disable kernel preemption.
+    fn disable_preempt();
+}
 
 /// A base trait that all guards implement.
 pub trait BaseGuard {
@@ -65,18 +212,78 @@ pub trait BaseGuard {
     fn release(state: Self::State);
 }
 
+/// A marker that makes the guards `!Send` and `!Sync`.
+///
+/// The saved state of a guard only makes sense on the CPU where it is created,
+/// so the guard must not be moved to or shared with other threads.
+///
+/// ```compile_fail
+/// fn assert_send<T: Send>() {}
+/// assert_send::<kernel_guard::NoOp>();
+/// ```
+///
+/// ```compile_fail
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<kernel_guard::NoOp>();
+/// ```
+///
+/// ```compile_fail
+/// fn assert_send<T: Send>() {}
+/// assert_send::<kernel_guard::IrqSave>();
+/// ```
+///
+/// ```compile_fail
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<kernel_guard::IrqSave>();
+/// ```
+///
+/// ```compile_fail
+/// fn assert_send<T: Send>() {}
+/// assert_send::<kernel_guard::NoPreempt>();
+/// ```
+///
+/// ```compile_fail
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<kernel_guard::NoPreempt>();
+/// ```
+///
+/// ```compile_fail
+/// fn assert_send<T: Send>() {}
+/// assert_send::<kernel_guard::NoPreemptIrqSave>();
+/// ```
+///
+/// ```compile_fail
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<kernel_guard::NoPreemptIrqSave>();
+/// ```
+type NotSendSync = PhantomData<*mut ()>;
+
 /// A no-op guard that does nothing around the critical section.
-pub struct NoOp;
-
-cfg_if::cfg_if! {
-    // For user-mode std apps, we use the alias of [`NoOp`] for all guards,
-    // since we can not disable IRQs or preemption in user-mode.
-    if #[cfg(any(target_os = "none", doc))] {
-        /// A guard that disables/enables local IRQs around the critical section.
-        pub struct IrqSave(usize);
-    } else {
-        /// Alias of [`NoOp`].
-        pub type IrqSave = NoOp;
+///
+/// Unlike the other guards, it can be created in const contexts.
+///
+/// ```
+/// use kernel_guard::NoOp;
+///
+/// const GUARD: NoOp = NoOp::new();
+/// drop(GUARD);
+/// ```
+pub struct NoOp {
+    _marker: NotSendSync,
+}
+
+impl NoOp {
+    /// Creates a new [`NoOp`] guard.
+    pub const fn new() -> Self {
+        Self {
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl Default for NoOp {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -86,52 +293,63 @@ impl BaseGuard for NoOp {
     fn release(_state: Self::State) {}
 }
 
-impl NoOp {
-    /// Creates a new [`NoOp`] guard.
-    pub const fn new() -> Self {
-        Self
-    }
-}
-
 impl Drop for NoOp {
     fn drop(&mut self) {}
 }
 
-#[cfg(any(target_os = "none", doc))]
-mod imp {
-    use super::*;
+/// A guard that disables/enables local IRQs around the critical section.
+///
+/// On hosted targets (not `target_os = "none"`), it only changes the emulated
+/// IRQ state (of the current thread with the feature `std`).
+pub type IrqSave = Guard<DisableIrqs>;
 
-    impl BaseGuard for IrqSave {
-        type State = usize;
+/// A guard that disables/enables kernel preemption around the critical
+/// section.
+pub type NoPreempt = Guard<DisablePreempt>;
 
-        #[inline]
-        fn acquire() -> Self::State {
-            super::arch::local_irq_save_and_disable()
-        }
+/// A guard that disables/enables both kernel preemption and local IRQs
+/// around the critical section.
+///
+/// When entering the critical section, it disables kernel preemption
+/// first, followed by local IRQs. When leaving the critical section, it
+/// re-enables local IRQs first, followed by kernel preemption.
+pub type NoPreemptIrqSave = Guard<(DisablePreempt, DisableIrqs)>;
 
-        #[inline]
-        fn release(state: Self::State) {
-            // restore IRQ states
-            super::arch::local_irq_restore(state);
-        }
-    }
+/// A guard that asserts local IRQs are already disabled, without changing the
+/// IRQ state.
+///
+/// It is used in hot paths (e.g., interrupt handlers) where IRQs are known to
+/// be disabled. The state is checked in debug builds only. The guard also
+/// lends the same [`IrqsDisabled`] token as [`IrqSave`], but the state is
+/// checked again in all builds when the token is borrowed.
+///
+/// # Examples
+///
+/// ```
+/// use kernel_guard::{AssertIrqsDisabled, IrqSave};
+///
+/// let _irq_guard = IrqSave::new();
+/// let guard = AssertIrqsDisabled::new();
+/// let _token = guard.irqs_disabled();
+/// ```
+pub type AssertIrqsDisabled = Guard<CheckIrqsDisabled>;
 
-    impl IrqSave {
-        /// Creates a new [`IrqSave`] guard.
-        pub fn new() -> Self {
-            Self(Self::acquire())
-        }
-    }
-
-    impl Drop for IrqSave {
-        fn drop(&mut self) {
-            Self::release(self.0)
-        }
-    }
-
-    impl Default for IrqSave {
-        fn default() -> Self {
-            Self::new()
-        }
-    }
-}
+/// A guard that asserts kernel preemption is already disabled, without
+/// changing it.
+///
+/// The state is checked in debug builds only, and only if it is known (see
+/// [`CheckPreemptDisabled`]). The guard also lends the same [`PreemptDisabled`]
+/// token as [`NoPreempt`], but the state is checked again in all builds when
+/// the token is borrowed, which requires it to be known (see
+/// [`Guard::<CheckPreemptDisabled>::preempt_disabled`](Guard::preempt_disabled)).
+///
+/// # Examples
+///
+/// ```
+/// use kernel_guard::{AssertPreemptDisabled, NoPreemptIrqSave};
+///
+/// let _preempt_guard = NoPreemptIrqSave::new();
+/// let guard = AssertPreemptDisabled::new();
+/// let _token = guard.preempt_disabled();
+/// ```
+pub type AssertPreemptDisabled = Guard<CheckPreemptDisabled>;
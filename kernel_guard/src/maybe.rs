@@ -0,0 +1,113 @@
+//! Guards that are chosen at runtime.
+
+use crate::guard::{DisableIrqs, DisablePreempt, Guard};
+use crate::token::{IrqsDisabled, IrqsOff, PreemptDisabled, PreemptOff};
+use crate::BaseGuard;
+
+/// A guard that acquires `G` only if the condition given at creation is true.
+///
+/// It saves both the choice and the state of `G`, and only releases `G` when
+/// dropped if `G` was acquired.
+///
+/// # Examples
+///
+/// ```
+/// use kernel_guard::{irqs_enabled, DisableIrqs, MaybeGuard};
+///
+/// fn log(in_isr: bool) {
+///     let guard = MaybeGuard::<DisableIrqs>::new(!in_isr);
+///     assert_eq!(guard.is_acquired(), !in_isr);
+///     // Write the log here
+/// }
+///
+/// log(false);
+/// log(true);
+/// assert!(irqs_enabled());
+/// ```
+pub struct MaybeGuard<G: BaseGuard>(Option<Guard<G>>);
+
+impl<G: BaseGuard> MaybeGuard<G> {
+    /// Creates a new guard, which acquires `G` if `condition` is true.
+    #[inline]
+    #[track_caller]
+    pub fn new(condition: bool) -> Self {
+        Self(if condition { Some(Guard::new()) } else { None })
+    }
+
+    /// Whether `G` was acquired when the guard was created.
+    #[inline]
+    pub fn is_acquired(&self) -> bool {
+        self.0.is_some()
+    }
+}
+
+impl<G: IrqsOff> MaybeGuard<G> {
+    /// Borrows a token proving that local IRQs are disabled, if `G` was
+    /// acquired.
+    #[inline]
+    pub fn irqs_disabled(&self) -> Option<&IrqsDisabled> {
+        self.0.as_ref().map(|guard| guard.irqs_disabled())
+    }
+}
+
+impl<G: PreemptOff> MaybeGuard<G> {
+    /// Borrows a token proving that kernel preemption is disabled, if `G` was
+    /// acquired.
+    #[inline]
+    pub fn preempt_disabled(&self) -> Option<&PreemptDisabled> {
+        self.0.as_ref().map(|guard| guard.preempt_disabled())
+    }
+}
+
+/// A guard that disables kernel preemption and/or local IRQs, selected at
+/// runtime.
+///
+/// Like [`NoPreemptIrqSave`](crate::NoPreemptIrqSave), preemption is disabled
+/// before IRQs, and enabled after them.
+///
+/// # Examples
+///
+/// ```
+/// use kernel_guard::{irqs_enabled, DynGuard};
+///
+/// let guard = DynGuard::new(true, false);
+/// assert!(guard.preempt_disabled().is_some());
+/// assert!(irqs_enabled());
+/// drop(guard);
+///
+/// let guard = DynGuard::new(true, true);
+/// assert!(guard.irqs_disabled().is_some());
+/// assert!(!irqs_enabled());
+/// ```
+pub struct DynGuard {
+    // Fields are dropped in order, so IRQs are restored before preemption.
+    irq: MaybeGuard<DisableIrqs>,
+    preempt: MaybeGuard<DisablePreempt>,
+}
+
+impl DynGuard {
+    /// Creates a new guard, which disables kernel preemption if
+    /// `disable_preempt` is true, and then local IRQs if `disable_irqs` is
+    /// true.
+    #[inline]
+    #[track_caller]
+    pub fn new(disable_preempt: bool, disable_irqs: bool) -> Self {
+        let preempt = MaybeGuard::new(disable_preempt);
+        let irq = MaybeGuard::new(disable_irqs);
+        Self { irq, preempt }
+    }
+
+    /// Borrows a token proving that local IRQs are disabled, if they were
+    /// selected to be disabled.
+    #[inline]
+    pub fn irqs_disabled(&self) -> Option<&IrqsDisabled> {
+        self.irq.irqs_disabled()
+    }
+
+    /// Borrows a token proving that kernel preemption is disabled, if it was
+    /// selected to be disabled.
+    #[inline]
+    pub fn preempt_disabled(&self) -> Option<&PreemptDisabled> {
+        self.preempt.preempt_disabled()
+    }
+}
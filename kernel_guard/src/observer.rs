@@ -0,0 +1,159 @@
+//! Observer of the built-in guards, enabled by the feature `observer`.
+
+use core::panic::Location;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::guard::GuardKind;
+
+/// An observer of the built-in guard operations ([`DisableIrqs`] and
+/// [`DisablePreempt`]), which are used by all the guards that disable local
+/// IRQs or kernel preemption.
+///
+/// The `state` passed to the observer is the raw IRQ state (see
+/// [`IrqState::into_raw`]) for [`GuardKind::Irq`], and 0 for
+/// [`GuardKind::Preempt`].
+///
+/// The observer is called inside the critical section, i.e., after the guard
+/// is acquired and before it is released, so it must not use the guards of
+/// this crate itself.
+///
+/// [`DisableIrqs`]: crate::DisableIrqs
+/// [`DisablePreempt`]: crate::DisablePreempt
+/// [`IrqState::into_raw`]: crate::IrqState::into_raw
+pub trait GuardObserver: Sync {
+    /// Called after a guard of `kind` is acquired at `location`.
+    fn on_acquire(&self, kind: GuardKind, state: usize, location: &'static Location<'static>);
+
+    /// Called before a guard of `kind` is released.
+    fn on_release(&self, kind: GuardKind, state: usize);
+}
+
+static OBSERVER: AtomicPtr<&'static dyn GuardObserver> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Registers the observer of the built-in guards, replacing the previously
+/// registered one.
+///
+/// The observer is referred to by a `static` item of type
+/// `&'static dyn GuardObserver`, so that it can be replaced atomically. It is
+/// global to all CPUs, and takes precedence over the one provided by
+/// [`GuardObserverIf`] if the feature `static-observer` is enabled. Before any
+/// observer is registered, nothing is called.
+///
+/// # Examples
+///
+/// ```
+/// use core::panic::Location;
+/// use core::sync::atomic::{AtomicUsize, Ordering};
+/// use kernel_guard::{GuardKind, GuardObserver, NoPreemptIrqSave};
+///
+/// struct Counter {
+///     acquired: AtomicUsize,
+///     released: AtomicUsize,
+/// }
+///
+/// impl GuardObserver for Counter {
+///     fn on_acquire(&self, _kind: GuardKind, _state: usize, location: &'static Location<'static>) {
+///         assert_eq!(location.file(), file!());
+///         self.acquired.fetch_add(1, Ordering::Relaxed);
+///     }
+///
+///     fn on_release(&self, _kind: GuardKind, _state: usize) {
+///         self.released.fetch_add(1, Ordering::Relaxed);
+///     }
+/// }
+///
+/// static COUNTER: Counter = Counter {
+///     acquired: AtomicUsize::new(0),
+///     released: AtomicUsize::new(0),
+/// };
+/// static OBSERVER: &dyn GuardObserver = &COUNTER;
+///
+/// kernel_guard::set_guard_observer(&OBSERVER);
+/// drop(NoPreemptIrqSave::new());
+/// assert_eq!(COUNTER.acquired.load(Ordering::Relaxed), 2);
+/// assert_eq!(COUNTER.released.load(Ordering::Relaxed), 2);
+///
+/// // Replace it with another one.
+/// static OTHER: Counter = Counter {
+///     acquired: AtomicUsize::new(0),
+///     released: AtomicUsize::new(0),
+/// };
+/// static OTHER_OBSERVER: &dyn GuardObserver = &OTHER;
+///
+/// kernel_guard::set_guard_observer(&OTHER_OBSERVER);
+/// drop(NoPreemptIrqSave::new());
+/// assert_eq!(COUNTER.acquired.load(Ordering::Relaxed), 2);
+/// assert_eq!(OTHER.acquired.load(Ordering::Relaxed), 2);
+/// ```
+pub fn set_guard_observer(observer: &'static &'static dyn GuardObserver) {
+    OBSERVER.store(observer as *const _ as *mut _, Ordering::Release);
+}
+
+/// The observer of the built-in guards provided at link time, used if the
+/// feature `static-observer` is enabled and no observer is registered by
+/// [`set_guard_observer`].
+///
+/// Like [`KernelGuardIf`](crate::KernelGuardIf), it is only called on bare
+/// metal (`target_os = "none"`).
+///
+/// # Examples
+///
+/// ```no_run
+/// use core::panic::Location;
+/// use kernel_guard::{GuardKind, GuardObserver, GuardObserverIf};
+///
+/// struct Tracer;
+///
+/// impl GuardObserver for Tracer {
+///     fn on_acquire(&self, _kind: GuardKind, _state: usize, _location: &'static Location<'static>) {
+///         // Your implementation here
+///     }
+///     fn on_release(&self, _kind: GuardKind, _state: usize) {
+///         // Your implementation here
+///     }
+/// }
+///
+/// struct GuardObserverIfImpl;
+///
+/// #[crate_interface::impl_interface]
+/// impl GuardObserverIf for GuardObserverIfImpl {
+///     fn observer() -> &'static dyn GuardObserver {
+///         &Tracer
+///     }
+/// }
+/// ```
+#[cfg(feature = "static-observer")]
+#[crate_interface::def_interface]
+pub trait GuardObserverIf {
+    /// Returns the observer of the built-in guards.
+    fn observer() -> &'static dyn GuardObserver;
+}
+
+#[inline]
+fn observer() -> Option<&'static dyn GuardObserver> {
+    // SAFETY: the pointer is either null or comes from a `&'static` reference.
+    match unsafe { OBSERVER.load(Ordering::Acquire).as_ref() } {
+        Some(observer) => Some(*observer),
+        #[cfg(all(feature = "static-observer", target_os = "none"))]
+        None => Some(crate_interface::call_interface!(GuardObserverIf::observer)),
+        #[cfg(not(all(feature = "static-observer", target_os = "none")))]
+        None => None,
+    }
+}
+
+/// Notifies the observer that a guard of `kind` is acquired by the caller.
+#[inline]
+#[track_caller]
+pub(crate) fn on_acquire(kind: GuardKind, state: usize) {
+    if let Some(observer) = observer() {
+        observer.on_acquire(kind, state, Location::caller());
+    }
+}
+
+/// Notifies the observer that a guard of `kind` is being released.
+#[inline]
+pub(crate) fn on_release(kind: GuardKind, state: usize) {
+    if let Some(observer) = observer() {
+        observer.on_release(kind, state);
+    }
+}
@@ -0,0 +1,245 @@
+//! Per-CPU data maintained by this crate.
+
+#[cfg(any(
+    feature = "debug-guards",
+    feature = "irqsoff-trace",
+    feature = "preemptoff-trace",
+    feature = "guard-stats"
+))]
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+#[cfg(any(
+    feature = "debug-guards",
+    feature = "irqsoff-trace",
+    feature = "preemptoff-trace",
+    feature = "guard-stats"
+))]
+use crate::guard::GuardKind;
+
+/// Per-CPU data maintained by this crate, required if the feature `percpu` is
+/// enabled.
+///
+/// The kernel must reserve one [`PerCpuData`] for each CPU, call
+/// [`PerCpuData::init`] on it, and make the architecture-specific per-CPU
+/// register point to it before any guard is used on that CPU:
+///
+/// - x86/x86_64: the `GS` segment base.
+/// - AArch64: the `TPIDR_EL1` register.
+/// - RISC-V: the `tp` register.
+///
+/// If the kernel has its own per-CPU area, the [`PerCpuData`] must be placed
+/// at the beginning of it (e.g., as the first field of a `#[repr(C)]` struct).
+///
+/// On hosted targets (not `target_os = "none"`), no initialization is needed:
+/// each thread acts as a CPU and owns its own [`PerCpuData`] with the feature
+/// `std`, otherwise all threads share one.
+#[repr(C)]
+pub struct PerCpuData {
+    /// Pointer to itself, so that x86 can get the base address by `gs:[0]`.
+    self_ptr: AtomicPtr<PerCpuData>,
+    pub(crate) preempt_count: AtomicUsize,
+    pub(crate) need_resched: AtomicBool,
+    /// Nesting depth of each kind of the built-in guards, shared by the
+    /// debugging features.
+    #[cfg(any(
+        feature = "debug-guards",
+        feature = "irqsoff-trace",
+        feature = "preemptoff-trace",
+        feature = "guard-stats"
+    ))]
+    pub(crate) depths: [AtomicUsize; GuardKind::COUNT],
+    #[cfg(feature = "debug-guards")]
+    pub(crate) debug: crate::debug::DebugData,
+    #[cfg(any(feature = "irqsoff-trace", feature = "preemptoff-trace"))]
+    pub(crate) trace: crate::trace::TraceData,
+    #[cfg(feature = "guard-stats")]
+    pub(crate) stats: crate::stats::StatsData,
+}
+
+/// Offset of the preemption count in [`PerCpuData`].
+#[cfg(feature = "preempt-count")]
+#[allow(dead_code)]
+pub(crate) const PREEMPT_COUNT_OFFSET: usize = core::mem::offset_of!(PerCpuData, preempt_count);
+
+/// Offset of the pending reschedule flag in [`PerCpuData`].
+#[cfg(feature = "preempt-count")]
+#[allow(dead_code)]
+pub(crate) const NEED_RESCHED_OFFSET: usize = core::mem::offset_of!(PerCpuData, need_resched);
+
+impl PerCpuData {
+    /// Creates a new [`PerCpuData`].
+    pub const fn new() -> Self {
+        Self {
+            self_ptr: AtomicPtr::new(core::ptr::null_mut()),
+            preempt_count: AtomicUsize::new(0),
+            need_resched: AtomicBool::new(false),
+            #[cfg(any(
+                feature = "debug-guards",
+                feature = "irqsoff-trace",
+                feature = "preemptoff-trace",
+                feature = "guard-stats"
+            ))]
+            depths: [const { AtomicUsize::new(0) }; GuardKind::COUNT],
+            #[cfg(feature = "debug-guards")]
+            debug: crate::debug::DebugData::new(),
+            #[cfg(any(feature = "irqsoff-trace", feature = "preemptoff-trace"))]
+            trace: crate::trace::TraceData::new(),
+            #[cfg(feature = "guard-stats")]
+            stats: crate::stats::StatsData::new(),
+        }
+    }
+
+    /// Initializes the [`PerCpuData`] at its final location.
+    pub fn init(&'static self) {
+        self.self_ptr
+            .store(self as *const _ as *mut _, Ordering::Relaxed);
+    }
+}
+
+impl Default for PerCpuData {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(target_os = "none")] {
+        /// Calls `f` with the [`PerCpuData`] of the current CPU.
+        ///
+        /// Local IRQs are disabled during the call, so the current task cannot
+        /// be migrated to another CPU in the middle.
+        #[inline]
+        #[allow(dead_code)]
+        pub(crate) fn with_local<R>(f: impl FnOnce(&PerCpuData) -> R) -> R {
+            let flags = crate::arch::local_irq_save_and_disable();
+            // SAFETY: the kernel has set the per-CPU register to an initialized
+            // `PerCpuData`, as required by the documentation.
+            let ret = f(unsafe { &*(crate::arch::local_percpu_base() as *const PerCpuData) });
+            crate::arch::local_irq_restore(flags);
+            ret
+        }
+    } else if #[cfg(feature = "std")] {
+        extern crate std;
+
+        std::thread_local! {
+            static LOCAL_PERCPU: PerCpuData = const { PerCpuData::new() };
+        }
+
+        /// Calls `f` with the [`PerCpuData`] of the current thread.
+        #[inline]
+        #[allow(dead_code)]
+        pub(crate) fn with_local<R>(f: impl FnOnce(&PerCpuData) -> R) -> R {
+            LOCAL_PERCPU.with(f)
+        }
+    } else {
+        static GLOBAL_PERCPU: PerCpuData = PerCpuData::new();
+
+        /// Calls `f` with the only [`PerCpuData`], shared by all threads.
+        #[inline]
+        #[allow(dead_code)]
+        pub(crate) fn with_local<R>(f: impl FnOnce(&PerCpuData) -> R) -> R {
+            f(&GLOBAL_PERCPU)
+        }
+    }
+}
+
+/// The CPU identifier of a saved state whose CPU is unknown, e.g., rebuilt by
+/// [`IrqState::from_raw`](crate::IrqState::from_raw).
+#[cfg(debug_assertions)]
+pub(crate) const UNKNOWN_CPU: usize = 0;
+
+/// Returns the identifier of the current CPU, i.e., the address of its
+/// [`PerCpuData`].
+#[cfg(debug_assertions)]
+#[inline]
+pub(crate) fn local_cpu_id() -> usize {
+    with_local(|data| data as *const PerCpuData as usize)
+}
+
+/// Checks that a saved state of a built-in guard is released on the CPU `cpu`
+/// where it was saved.
+#[cfg(debug_assertions)]
+#[inline]
+#[track_caller]
+pub(crate) fn check_cpu(cpu: usize) {
+    assert!(
+        cpu == UNKNOWN_CPU || cpu == local_cpu_id(),
+        "guard state released on another CPU than where it was saved"
+    );
+}
+
+/// Records a built-in guard of `kind` acquired by the caller for all the
+/// debugging features, with a single access to the per-CPU data.
+///
+/// `_irqs_enabled` is whether local IRQs were enabled before a guard of
+/// [`GuardKind::Irq`] is acquired. Returns the slot of the guard in the stack
+/// of active guards, which is only meaningful with the feature `debug-guards`.
+#[cfg(any(
+    feature = "debug-guards",
+    feature = "irqsoff-trace",
+    feature = "preemptoff-trace",
+    feature = "guard-stats"
+))]
+#[inline]
+#[track_caller]
+pub(crate) fn on_acquire(kind: GuardKind, _irqs_enabled: bool) -> u8 {
+    let _location = Location::caller();
+    with_local(|data| {
+        let depth = &data.depths[kind as usize];
+        let count = depth.load(Ordering::Relaxed) + 1;
+        #[cfg(feature = "debug-guards")]
+        let slot = crate::debug::on_acquire(&data.debug, kind, count, _location);
+        #[cfg(not(feature = "debug-guards"))]
+        let slot = 0;
+        depth.store(count, Ordering::Relaxed);
+        #[cfg(any(feature = "irqsoff-trace", feature = "preemptoff-trace"))]
+        crate::trace::on_acquire(&data.trace, kind, count, _irqs_enabled, _location);
+        #[cfg(feature = "guard-stats")]
+        crate::stats::on_acquire(&data.stats, kind, count);
+        slot
+    })
+}
+
+/// Records a built-in guard of `kind` in `_slot` released for all the
+/// debugging features, with a single access to the per-CPU data.
+///
+/// `_irqs_enabled` is whether local IRQs are enabled again after a guard of
+/// [`GuardKind::Irq`] is released, and `_slot` is only used with the feature
+/// `debug-guards`. The reports (e.g., of a LIFO violation or
+/// an exceeded budget) are made after the access.
+#[cfg(any(
+    feature = "debug-guards",
+    feature = "irqsoff-trace",
+    feature = "preemptoff-trace",
+    feature = "guard-stats"
+))]
+#[inline]
+pub(crate) fn on_release(kind: GuardKind, _slot: u8, _irqs_enabled: bool) {
+    let (_violation, _section) = with_local(|data| {
+        let depth = &data.depths[kind as usize];
+        let count = depth.load(Ordering::Relaxed);
+        #[cfg(feature = "debug-guards")]
+        let violation = crate::debug::on_release(&data.debug, kind, count, _slot);
+        #[cfg(not(feature = "debug-guards"))]
+        let violation = ();
+        debug_assert!(
+            count > 0,
+            "{kind:?} guard released without a matching acquire"
+        );
+        depth.store(count - 1, Ordering::Relaxed);
+        #[cfg(any(feature = "irqsoff-trace", feature = "preemptoff-trace"))]
+        let section = crate::trace::on_release(&data.trace, kind, count, _irqs_enabled);
+        #[cfg(not(any(feature = "irqsoff-trace", feature = "preemptoff-trace")))]
+        let section = ();
+        (violation, section)
+    });
+    #[cfg(feature = "watchdog")]
+    if let Some((duration, Some(location))) = _section {
+        crate::watchdog::check(kind, duration, location);
+    }
+    #[cfg(feature = "debug-guards")]
+    if let Some((released, expected)) = _violation {
+        crate::debug::lifo_violation(kind, released, expected);
+    }
+}
@@ -0,0 +1,376 @@
+#[cfg(any(feature = "preempt-hooks", feature = "preempt-count"))]
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+/// Preemption hooks that can be registered at runtime, used instead of
+/// [`KernelGuardIf`](crate::KernelGuardIf) if the feature `preempt-hooks` is
+/// enabled.
+///
+/// If the feature `preempt-count` is also enabled, the hooks are called in
+/// addition to updating the built-in preemption count.
+///
+/// # Examples
+///
+/// ```
+/// use kernel_guard::PreemptHooks;
+///
+/// static BOOT_HOOKS: PreemptHooks = PreemptHooks {
+///     enable_preempt: || { /* Your implementation here */ },
+///     disable_preempt: || { /* Your implementation here */ },
+///     enable_preempt_no_resched: || { /* Your implementation here */ },
+/// };
+///
+/// kernel_guard::set_preempt_hooks(&BOOT_HOOKS);
+/// ```
+///
+/// The hooks are called by every guard that disables preemption:
+///
+/// ```
+/// use core::sync::atomic::{AtomicUsize, Ordering};
+/// use kernel_guard::{NoPreempt, PreemptHooks};
+///
+/// static DEPTH: AtomicUsize = AtomicUsize::new(0);
+/// static HOOKS: PreemptHooks = PreemptHooks {
+///     enable_preempt: || {
+///         DEPTH.fetch_sub(1, Ordering::Relaxed);
+///     },
+///     disable_preempt: || {
+///         DEPTH.fetch_add(1, Ordering::Relaxed);
+///     },
+///     enable_preempt_no_resched: || {
+///         DEPTH.fetch_sub(1, Ordering::Relaxed);
+///     },
+/// };
+///
+/// kernel_guard::set_preempt_hooks(&HOOKS);
+/// let guard = NoPreempt::new();
+/// assert_eq!(DEPTH.load(Ordering::Relaxed), 1);
+/// drop(guard);
+/// assert_eq!(DEPTH.load(Ordering::Relaxed), 0);
+/// ```
+#[cfg(feature = "preempt-hooks")]
+pub struct PreemptHooks {
+    /// How to enable kernel preemption.
+    pub enable_preempt: fn(),
+    /// How to disable kernel preemption.
+    pub disable_preempt: fn(),
+    /// How to enable kernel preemption without rescheduling, even if a
+    /// reschedule is pending.
+    pub enable_preempt_no_resched: fn(),
+}
+
+#[cfg(feature = "preempt-hooks")]
+static PREEMPT_HOOKS: AtomicPtr<PreemptHooks> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Registers the preemption hooks, replacing the previously registered ones.
+///
+/// Before any hooks are registered, the preemption enable/disable operations
+/// are no-ops.
+///
+/// The hooks are global to all CPUs, so they should not be replaced while
+/// some CPU is inside a critical section with preemption disabled.
+#[cfg(feature = "preempt-hooks")]
+pub fn set_preempt_hooks(hooks: &'static PreemptHooks) {
+    PREEMPT_HOOKS.store(hooks as *const _ as *mut _, Ordering::Release);
+}
+
+#[cfg(feature = "preempt-hooks")]
+#[inline]
+fn preempt_hooks() -> Option<&'static PreemptHooks> {
+    // SAFETY: the pointer is either null or comes from a `&'static` reference.
+    unsafe { PREEMPT_HOOKS.load(Ordering::Acquire).as_ref() }
+}
+
+#[cfg(feature = "preempt-count")]
+static RESCHED_HOOK: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Registers the hook that reschedules the current CPU, replacing the
+/// previously registered one.
+///
+/// It is called when the outermost preemption-disabling guard is released with
+/// local IRQs enabled, and a reschedule is pending (see [`set_need_resched`]).
+/// Before any hook is registered, nothing is called.
+///
+/// # Examples
+///
+/// ```
+/// use core::sync::atomic::{AtomicUsize, Ordering};
+/// use kernel_guard::{need_resched, set_need_resched, IrqSave, NoPreempt};
+///
+/// static RESCHED: AtomicUsize = AtomicUsize::new(0);
+/// kernel_guard::set_resched_hook(|| {
+///     RESCHED.fetch_add(1, Ordering::Relaxed);
+/// });
+///
+/// // Only the outermost release reschedules, and only once.
+/// set_need_resched();
+/// let outer = NoPreempt::new();
+/// drop(NoPreempt::new());
+/// assert_eq!(RESCHED.load(Ordering::Relaxed), 0);
+/// drop(outer);
+/// assert_eq!(RESCHED.load(Ordering::Relaxed), 1);
+/// assert!(!need_resched());
+/// drop(NoPreempt::new());
+/// assert_eq!(RESCHED.load(Ordering::Relaxed), 1);
+///
+/// // Never reschedules with local IRQs disabled.
+/// set_need_resched();
+/// let irq_guard = IrqSave::new();
+/// drop(NoPreempt::new());
+/// drop(irq_guard);
+/// assert_eq!(RESCHED.load(Ordering::Relaxed), 1);
+/// assert!(need_resched());
+/// ```
+///
+/// [`NoPreempt::release_no_resched`](crate::Guard::release_no_resched) keeps
+/// the reschedule pending:
+///
+/// ```
+/// use core::sync::atomic::{AtomicUsize, Ordering};
+/// use kernel_guard::{need_resched, set_need_resched, NoPreempt};
+///
+/// static RESCHED: AtomicUsize = AtomicUsize::new(0);
+/// kernel_guard::set_resched_hook(|| {
+///     RESCHED.fetch_add(1, Ordering::Relaxed);
+/// });
+///
+/// set_need_resched();
+/// NoPreempt::new().release_no_resched();
+/// assert_eq!(RESCHED.load(Ordering::Relaxed), 0);
+/// assert!(need_resched());
+/// ```
+#[cfg(feature = "preempt-count")]
+pub fn set_resched_hook(hook: fn()) {
+    RESCHED_HOOK.store(hook as *mut (), Ordering::Release);
+}
+
+/// Marks that the current CPU needs to be rescheduled as soon as it becomes
+/// preemptible.
+#[cfg(feature = "preempt-count")]
+#[inline]
+pub fn set_need_resched() {
+    crate::percpu::with_local(|data| data.need_resched.store(true, Ordering::Relaxed));
+}
+
+/// Clears the pending reschedule of the current CPU, e.g., when the scheduler
+/// has just rescheduled it by other means.
+#[cfg(feature = "preempt-count")]
+#[inline]
+pub fn clear_need_resched() {
+    crate::percpu::with_local(|data| data.need_resched.store(false, Ordering::Relaxed));
+}
+
+/// Whether a reschedule is pending on the current CPU.
+#[cfg(feature = "preempt-count")]
+#[inline]
+pub fn need_resched() -> bool {
+    crate::percpu::with_local(|data| data.need_resched.load(Ordering::Relaxed))
+}
+
+/// Returns the preemption count of the current CPU, i.e., the nesting depth
+/// of preemption disabling.
+///
+/// # Examples
+///
+/// ```
+/// use kernel_guard::{in_atomic, preempt_count, preemptible, NoPreempt, NoPreemptIrqSave};
+///
+/// assert_eq!(preempt_count(), 0);
+/// assert!(preemptible() && !in_atomic());
+///
+/// let outer = NoPreempt::new();
+/// let inner = NoPreemptIrqSave::new();
+/// assert_eq!(preempt_count(), 2);
+/// assert!(in_atomic() && !preemptible());
+///
+/// drop(inner);
+/// assert_eq!(preempt_count(), 1);
+/// drop(outer);
+/// assert_eq!(preempt_count(), 0);
+/// assert!(preemptible() && !in_atomic());
+/// ```
+#[cfg(feature = "preempt-count")]
+#[inline]
+pub fn preempt_count() -> usize {
+    crate::percpu::with_local(|data| data.preempt_count.load(Ordering::Relaxed))
+}
+
+/// Whether the current CPU can be preempted, i.e., the preemption count is
+/// zero.
+#[cfg(feature = "preempt-count")]
+#[inline]
+pub fn preemptible() -> bool {
+    preempt_count() == 0
+}
+
+/// Whether the current CPU is in atomic context, i.e., the preemption count
+/// is not zero.
+///
+/// [`AssertPreemptDisabled`](crate::AssertPreemptDisabled) checks it in debug
+/// builds (along with whether local IRQs are disabled):
+///
+/// ```
+/// use kernel_guard::{in_atomic, AssertPreemptDisabled, IrqSave, NoPreempt};
+///
+/// let irq_guard = IrqSave::new();
+/// drop(AssertPreemptDisabled::new());
+/// drop(irq_guard);
+///
+/// let _guard = NoPreempt::new();
+/// assert!(in_atomic());
+/// drop(AssertPreemptDisabled::new());
+/// ```
+#[cfg(feature = "preempt-count")]
+#[inline]
+pub fn in_atomic() -> bool {
+    preempt_count() != 0
+}
+
+/// Whether kernel preemption is known to be disabled on the current CPU.
+///
+/// Local IRQs being disabled also prevents preemption. Otherwise, it can only
+/// be known from the built-in preemption count, so it always returns `true`
+/// without the feature `preempt-count`.
+#[inline]
+pub(crate) fn preempt_disabled() -> bool {
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "preempt-count")] {
+            in_atomic() || !crate::arch::irqs_enabled()
+        } else {
+            true
+        }
+    }
+}
+
+/// Whether kernel preemption is proven to be disabled on the current CPU.
+///
+/// Unlike [`preempt_disabled`], it returns `false` if the preemption state is
+/// unknown, i.e., with the feature `preempt` but without `preempt-count`, and
+/// local IRQs are enabled. Without the feature `preempt`, the kernel is not
+/// preemptive at all.
+#[inline]
+pub(crate) fn preempt_proven_disabled() -> bool {
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "preempt-count")] {
+            preempt_disabled()
+        } else if #[cfg(feature = "preempt")] {
+            !crate::arch::irqs_enabled()
+        } else {
+            true
+        }
+    }
+}
+
+// On x86, the per-CPU count is updated by a single instruction relative to
+// `GS`. Other architectures have to read the per-CPU base from a register
+// first, so `with_local` disables local IRQs around the update to keep the
+// task from being migrated in the middle. Only the local CPU modifies the
+// count, so a plain load/store pair is enough there.
+
+#[cfg(feature = "preempt-count")]
+#[inline]
+fn preempt_count_add() {
+    cfg_if::cfg_if! {
+        if #[cfg(all(target_os = "none", any(target_arch = "x86", target_arch = "x86_64")))] {
+            crate::arch::preempt_count_inc();
+        } else {
+            crate::percpu::with_local(|data| {
+                let count = data.preempt_count.load(Ordering::Relaxed);
+                data.preempt_count.store(count + 1, Ordering::Relaxed);
+            });
+        }
+    }
+}
+
+#[cfg(feature = "preempt-count")]
+#[inline]
+fn preempt_count_sub(resched: bool) {
+    // never reschedule with local IRQs disabled, e.g., when a `NoPreempt` is
+    // released inside an `IrqSave` section.
+    let resched = resched && crate::arch::irqs_enabled();
+    debug_assert!(preempt_count() > 0, "preemption count underflow");
+    cfg_if::cfg_if! {
+        if #[cfg(all(target_os = "none", any(target_arch = "x86", target_arch = "x86_64")))] {
+            // The flag is usually clear, so it is only taken with local IRQs
+            // disabled if it is set.
+            let need_resched = crate::arch::preempt_count_dec()
+                && resched
+                && crate::arch::need_resched()
+                && crate::percpu::with_local(|data| {
+                    data.need_resched.swap(false, Ordering::Relaxed)
+                });
+        } else {
+            let need_resched = crate::percpu::with_local(|data| {
+                let count = data.preempt_count.load(Ordering::Relaxed);
+                data.preempt_count.store(count - 1, Ordering::Relaxed);
+                if resched && count == 1 && data.need_resched.load(Ordering::Relaxed) {
+                    data.need_resched.store(false, Ordering::Relaxed);
+                    true
+                } else {
+                    false
+                }
+            });
+        }
+    }
+    if need_resched {
+        let hook = RESCHED_HOOK.load(Ordering::Acquire);
+        if !hook.is_null() {
+            // SAFETY: the pointer is either null or comes from a `fn()`.
+            let hook: fn() = unsafe { core::mem::transmute(hook) };
+            hook();
+        }
+    }
+}
+
+#[inline]
+pub(crate) fn disable_preempt() {
+    #[cfg(feature = "preempt-count")]
+    preempt_count_add();
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "preempt-hooks")] {
+            if let Some(hooks) = preempt_hooks() {
+                (hooks.disable_preempt)();
+            }
+        } else if #[cfg(all(
+            feature = "preempt",
+            not(feature = "preempt-count"),
+            target_os = "none"
+        ))] {
+            crate_interface::call_interface!(crate::KernelGuardIf::disable_preempt);
+        }
+    }
+}
+
+#[inline]
+pub(crate) fn enable_preempt() {
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "preempt-hooks")] {
+            if let Some(hooks) = preempt_hooks() {
+                (hooks.enable_preempt)();
+            }
+        } else if #[cfg(all(
+            feature = "preempt",
+            not(feature = "preempt-count"),
+            target_os = "none"
+        ))] {
+            crate_interface::call_interface!(crate::KernelGuardIf::enable_preempt);
+        }
+    }
+    #[cfg(feature = "preempt-count")]
+    preempt_count_sub(true);
+}
+
+/// Same as [`enable_preempt`], but never reschedules even if a reschedule is
+/// pending.
+///
+/// [`KernelGuardIf`](crate::KernelGuardIf) can not tell the two apart, so it
+/// is only available with the built-in preemption count or the runtime hooks.
+#[cfg(any(feature = "preempt-count", feature = "preempt-hooks"))]
+#[inline]
+pub(crate) fn enable_preempt_no_resched() {
+    #[cfg(feature = "preempt-hooks")]
+    if let Some(hooks) = preempt_hooks() {
+        (hooks.enable_preempt_no_resched)();
+    }
+    #[cfg(feature = "preempt-count")]
+    preempt_count_sub(false);
+}
@@ -0,0 +1,106 @@
+//! Statistics of the built-in guards, enabled by the feature `guard-stats`.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::guard::{GuardKind, TrackedGuard};
+use crate::percpu::{with_local, PerCpuData};
+
+/// Statistics of a kind of built-in guards on a CPU.
+///
+/// The counters are pointer-sized, so they wrap around at 32 bits on 32-bit
+/// targets.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GuardStats {
+    /// The number of the acquired guards.
+    pub acquisitions: usize,
+    /// The number of the acquired guards that are not nested in another guard
+    /// of the same kind.
+    pub outermost: usize,
+    /// The maximum nesting depth.
+    pub max_depth: usize,
+}
+
+struct KindStats {
+    acquisitions: AtomicUsize,
+    outermost: AtomicUsize,
+    max_depth: AtomicUsize,
+}
+
+/// Per-CPU data for the statistics.
+pub(crate) struct StatsData {
+    kinds: [KindStats; GuardKind::COUNT],
+}
+
+impl StatsData {
+    pub(crate) const fn new() -> Self {
+        Self {
+            kinds: [const {
+                KindStats {
+                    acquisitions: AtomicUsize::new(0),
+                    outermost: AtomicUsize::new(0),
+                    max_depth: AtomicUsize::new(0),
+                }
+            }; GuardKind::COUNT],
+        }
+    }
+
+    fn snapshot(&self, kind: GuardKind) -> GuardStats {
+        let stats = &self.kinds[kind as usize];
+        GuardStats {
+            acquisitions: stats.acquisitions.load(Ordering::Relaxed),
+            outermost: stats.outermost.load(Ordering::Relaxed),
+            max_depth: stats.max_depth.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Returns the statistics of the guards of the same kind as `G` on the
+/// current CPU.
+///
+/// # Examples
+///
+/// ```
+/// use kernel_guard::{guard_stats, GuardStats, IrqSave, NoPreempt};
+///
+/// let guard = IrqSave::new();
+/// drop(IrqSave::new());
+/// drop(guard);
+/// drop(IrqSave::new());
+///
+/// let stats = guard_stats::<IrqSave>();
+/// assert_eq!(stats.acquisitions, 3);
+/// assert_eq!(stats.outermost, 2);
+/// assert_eq!(stats.max_depth, 2);
+/// assert_eq!(guard_stats::<NoPreempt>(), GuardStats::default());
+/// ```
+pub fn guard_stats<G: TrackedGuard>() -> GuardStats {
+    with_local(|data| data.stats.snapshot(G::KIND))
+}
+
+/// Returns the statistics of the guards of the same kind as `G` on the CPU
+/// that owns `data`.
+///
+/// It can be called on any CPU, but the results may be inconsistent if that
+/// CPU is updating them at the same time.
+pub fn guard_stats_of<G: TrackedGuard>(data: &PerCpuData) -> GuardStats {
+    data.stats.snapshot(G::KIND)
+}
+
+/// Records a guard of `kind` acquired, which makes the nesting depth `depth`.
+#[inline]
+pub(crate) fn on_acquire(data: &StatsData, kind: GuardKind, depth: usize) {
+    let stats = &data.kinds[kind as usize];
+    let acquisitions = stats.acquisitions.load(Ordering::Relaxed);
+    stats
+        .acquisitions
+        .store(acquisitions.wrapping_add(1), Ordering::Relaxed);
+    if depth == 1 {
+        let outermost = stats.outermost.load(Ordering::Relaxed);
+        stats
+            .outermost
+            .store(outermost.wrapping_add(1), Ordering::Relaxed);
+    }
+    if depth > stats.max_depth.load(Ordering::Relaxed) {
+        stats.max_depth.store(depth, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,186 @@
+//! Zero-sized tokens proving that local IRQs or preemption are disabled.
+
+use core::marker::PhantomData;
+
+use crate::guard::{CheckIrqsDisabled, CheckPreemptDisabled, DisableIrqs, DisablePreempt, Guard};
+use crate::{BaseGuard, NotSendSync};
+
+/// A token proving that local IRQs are disabled on the current CPU.
+///
+/// It can only be borrowed from a guard that disables IRQs (see [`IrqsOff`]),
+/// or from [`AssertIrqsDisabled`](crate::AssertIrqsDisabled) after checking
+/// that IRQs are disabled, and lives no longer than the guard. Functions that require IRQs to be
+/// disabled can take `&IrqsDisabled` as a parameter to let the type system
+/// check it.
+///
+/// # Examples
+///
+/// ```
+/// use kernel_guard::{IrqSave, IrqsDisabled};
+///
+/// fn percpu_op(_token: &IrqsDisabled) {
+///     // Access per-CPU data here
+/// }
+///
+/// let guard = IrqSave::new();
+/// percpu_op(guard.irqs_disabled());
+/// drop(guard);
+/// ```
+///
+/// The token can not outlive the guard:
+///
+/// ```compile_fail
+/// use kernel_guard::{IrqSave, IrqsDisabled};
+///
+/// fn percpu_op(_token: &IrqsDisabled) {}
+///
+/// let guard = IrqSave::new();
+/// let token = guard.irqs_disabled();
+/// drop(guard);
+/// percpu_op(token);
+/// ```
+///
+/// And can not be borrowed from guards that keep IRQs enabled:
+///
+/// ```compile_fail
+/// let guard = kernel_guard::NoPreempt::new();
+/// let token = guard.irqs_disabled();
+/// ```
+pub struct IrqsDisabled(NotSendSync);
+
+/// A token proving that kernel preemption is disabled on the current CPU.
+///
+/// It can only be borrowed from a guard that disables preemption (see
+/// [`PreemptOff`]), or from
+/// [`AssertPreemptDisabled`](crate::AssertPreemptDisabled) after checking
+/// that preemption is disabled, and lives no longer than the guard.
+///
+/// # Examples
+///
+/// ```
+/// use kernel_guard::{NoPreempt, PreemptDisabled};
+///
+/// fn sched_op(_token: &PreemptDisabled) {
+///     // Access the run queue here
+/// }
+///
+/// let guard = NoPreempt::new();
+/// sched_op(guard.preempt_disabled());
+/// drop(guard);
+/// ```
+pub struct PreemptDisabled(NotSendSync);
+
+impl IrqsDisabled {
+    #[inline]
+    pub(crate) const fn new() -> &'static Self {
+        &Self(PhantomData)
+    }
+}
+
+impl PreemptDisabled {
+    #[inline]
+    pub(crate) const fn new() -> &'static Self {
+        &Self(PhantomData)
+    }
+}
+
+/// Guard operations that keep local IRQs disabled inside the critical section.
+///
+/// For tuples, the last element decides whether the composed operations keep
+/// IRQs disabled, as IRQs are usually the last to be disabled (e.g.,
+/// [`NoPreemptIrqSave`](crate::NoPreemptIrqSave)).
+///
+/// # Safety
+///
+/// Local IRQs must be disabled from the end of [`BaseGuard::acquire`] to the
+/// beginning of [`BaseGuard::release`].
+pub unsafe trait IrqsOff: BaseGuard {}
+
+/// Guard operations that keep kernel preemption disabled inside the critical
+/// section.
+///
+/// For tuples, the first element decides whether the composed operations keep
+/// preemption disabled, as preemption is usually the first to be disabled
+/// (e.g., [`NoPreemptIrqSave`](crate::NoPreemptIrqSave)).
+///
+/// # Safety
+///
+/// Kernel preemption must be disabled from the end of [`BaseGuard::acquire`]
+/// to the beginning of [`BaseGuard::release`].
+pub unsafe trait PreemptOff: BaseGuard {}
+
+unsafe impl IrqsOff for DisableIrqs {}
+unsafe impl PreemptOff for DisablePreempt {}
+unsafe impl<G: IrqsOff> IrqsOff for Guard<G> {}
+unsafe impl<G: PreemptOff> PreemptOff for Guard<G> {}
+
+macro_rules! impl_tuple_token {
+    ($first:ident, $($ty:ident),*; $last:ident) => {
+        unsafe impl<$($ty: BaseGuard),*> IrqsOff for ($($ty,)*) where $last: IrqsOff {}
+        unsafe impl<$($ty: BaseGuard),*> PreemptOff for ($($ty,)*) where $first: PreemptOff {}
+    };
+}
+
+impl_tuple_token!(A, A, B; B);
+impl_tuple_token!(A, A, B, C; C);
+impl_tuple_token!(A, A, B, C, D; D);
+impl_tuple_token!(A, A, B, C, D, E; E);
+impl_tuple_token!(A, A, B, C, D, E, F; F);
+impl_tuple_token!(A, A, B, C, D, E, F, G; G);
+impl_tuple_token!(A, A, B, C, D, E, F, G, H; H);
+
+impl<G: IrqsOff> Guard<G> {
+    /// Borrows a token proving that local IRQs are disabled while the guard
+    /// is held.
+    #[inline]
+    pub fn irqs_disabled(&self) -> &IrqsDisabled {
+        IrqsDisabled::new()
+    }
+}
+
+impl<G: PreemptOff> Guard<G> {
+    /// Borrows a token proving that kernel preemption is disabled while the
+    /// guard is held.
+    #[inline]
+    pub fn preempt_disabled(&self) -> &PreemptDisabled {
+        PreemptDisabled::new()
+    }
+}
+
+// The assertion guards only check the state in debug builds, so they are not
+// `IrqsOff`/`PreemptOff`, and check it again whenever a token is borrowed.
+
+impl Guard<CheckIrqsDisabled> {
+    /// Borrows a token proving that local IRQs are disabled while the guard
+    /// is held.
+    ///
+    /// # Panics
+    ///
+    /// Panics if local IRQs are enabled, in release builds as well.
+    #[inline]
+    #[track_caller]
+    pub fn irqs_disabled(&self) -> &IrqsDisabled {
+        assert!(!crate::irqs_enabled(), "local IRQs are not disabled");
+        IrqsDisabled::new()
+    }
+}
+
+impl Guard<CheckPreemptDisabled> {
+    /// Borrows a token proving that kernel preemption is disabled while the
+    /// guard is held.
+    ///
+    /// # Panics
+    ///
+    /// Panics if kernel preemption is not known to be disabled, in release
+    /// builds as well. With the feature `preempt` but without `preempt-count`,
+    /// it is only known if local IRQs are disabled.
+    #[inline]
+    #[track_caller]
+    pub fn preempt_disabled(&self) -> &PreemptDisabled {
+        assert!(
+            crate::preempt::preempt_proven_disabled(),
+            "kernel preemption is not disabled"
+        );
+        PreemptDisabled::new()
+    }
+}
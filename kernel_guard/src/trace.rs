@@ -0,0 +1,323 @@
+//! Latency tracing of the critical sections, enabled by the features
+//! `irqsoff-trace` and `preemptoff-trace`.
+//!
+//! Durations are measured in the ticks of the architecture-specific timestamp
+//! source:
+//!
+//! - x86/x86_64: the timestamp counter (`rdtsc`).
+//! - AArch64: the virtual count of the generic timer (`CNTVCT_EL0`).
+//! - RISC-V: the `time` CSR (`rdtime`).
+//! - Hosted targets (not `target_os = "none"`): nanoseconds with the feature
+//!   `std`, otherwise always zero.
+//!
+//! All the values are kept in pointer-sized per-CPU storage, so timestamps and
+//! counters wrap around at 32 bits on 32-bit targets.
+
+use core::panic::Location;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use crate::arch::read_timestamp;
+use crate::guard::GuardKind;
+use crate::percpu::{with_local, PerCpuData};
+
+/// Number of the buckets in a latency histogram.
+#[cfg(feature = "irqsoff-trace")]
+pub const HIST_BUCKETS: usize = usize::BITS as usize;
+
+/// Statistics of the sections with local IRQs disabled by
+/// [`IrqSave`](crate::IrqSave).
+///
+/// Only the outermost sections, i.e., the ones entered with IRQs enabled, are
+/// measured.
+///
+/// # Examples
+///
+/// ```
+/// use kernel_guard::IrqSave;
+///
+/// let guard = IrqSave::new();
+/// let _nested = IrqSave::new();
+/// drop(_nested);
+/// drop(guard);
+///
+/// let irqs_off = kernel_guard::trace::snapshot().irqs_off;
+/// assert_eq!(irqs_off.count(), 1);
+/// assert_eq!(irqs_off.max_location.unwrap().line(), line!() - 7);
+/// ```
+#[cfg(feature = "irqsoff-trace")]
+#[derive(Clone, Copy, Debug)]
+pub struct IrqsOffTrace {
+    /// Log2 histogram of the durations: `histogram[i]` counts the sections
+    /// lasting `2^i` to `2^(i+1) - 1` ticks, and `histogram[0]` also counts
+    /// the ones lasting 0 ticks.
+    pub histogram: [usize; HIST_BUCKETS],
+    /// The longest duration.
+    pub max: usize,
+    /// Where the guard of the longest section was created.
+    pub max_location: Option<&'static Location<'static>>,
+}
+
+#[cfg(feature = "irqsoff-trace")]
+impl IrqsOffTrace {
+    /// Returns the number of the measured sections.
+    pub fn count(&self) -> usize {
+        self.histogram
+            .iter()
+            .fold(0, |count, &n| count.wrapping_add(n))
+    }
+}
+
+/// Statistics of the sections with kernel preemption disabled by
+/// [`NoPreempt`](crate::NoPreempt) and the other guards that disable
+/// preemption.
+///
+/// Only the outermost sections, i.e., the ones entered when no such guard is
+/// held on the CPU, are measured.
+///
+/// # Examples
+///
+/// ```
+/// use kernel_guard::{NoPreempt, NoPreemptIrqSave};
+///
+/// let guard = NoPreempt::new();
+/// let _nested = NoPreemptIrqSave::new();
+/// drop(_nested);
+/// drop(guard);
+///
+/// let preempt_off = kernel_guard::trace::snapshot().preempt_off;
+/// assert_eq!(preempt_off.count, 1);
+/// assert_eq!(preempt_off.max_location.unwrap().line(), line!() - 7);
+///
+/// kernel_guard::trace::reset();
+/// let preempt_off = kernel_guard::trace::snapshot().preempt_off;
+/// assert_eq!(preempt_off.count, 0);
+/// assert!(preempt_off.max_location.is_none());
+/// ```
+#[cfg(feature = "preemptoff-trace")]
+#[derive(Clone, Copy, Debug)]
+pub struct PreemptOffTrace {
+    /// The number of the measured sections.
+    pub count: usize,
+    /// The longest duration.
+    pub max: usize,
+    /// Where the guard of the longest section was created.
+    pub max_location: Option<&'static Location<'static>>,
+}
+
+/// Tracing results of a CPU.
+#[derive(Clone, Copy, Debug)]
+pub struct Snapshot {
+    /// Statistics of the IRQs-off sections.
+    #[cfg(feature = "irqsoff-trace")]
+    pub irqs_off: IrqsOffTrace,
+    /// Statistics of the preemption-off sections.
+    #[cfg(feature = "preemptoff-trace")]
+    pub preempt_off: PreemptOffTrace,
+}
+
+/// Timing of a kind of critical sections on a CPU.
+struct SectionData {
+    start: AtomicUsize,
+    location: AtomicPtr<Location<'static>>,
+    max: AtomicUsize,
+    max_location: AtomicPtr<Location<'static>>,
+}
+
+impl SectionData {
+    const fn new() -> Self {
+        Self {
+            start: AtomicUsize::new(0),
+            location: AtomicPtr::new(core::ptr::null_mut()),
+            max: AtomicUsize::new(0),
+            max_location: AtomicPtr::new(core::ptr::null_mut()),
+        }
+    }
+
+    fn enter(&self, location: &'static Location<'static>) {
+        self.location
+            .store(location as *const _ as *mut _, Ordering::Relaxed);
+        self.start.store(read_timestamp(), Ordering::Relaxed);
+    }
+
+    /// Records the section as ended, and returns its duration and where it was
+    /// entered.
+    fn exit(&self) -> (usize, Option<&'static Location<'static>>) {
+        let duration = read_timestamp().wrapping_sub(self.start.load(Ordering::Relaxed));
+        let location = self.location.load(Ordering::Relaxed);
+        if duration >= self.max.load(Ordering::Relaxed) {
+            self.max.store(duration, Ordering::Relaxed);
+            self.max_location.store(location, Ordering::Relaxed);
+        }
+        // SAFETY: the pointer is either null or comes from a `&'static Location`.
+        (duration, unsafe { location.as_ref() })
+    }
+
+    fn max_location(&self) -> Option<&'static Location<'static>> {
+        // SAFETY: the pointer is either null or comes from a `&'static Location`.
+        unsafe { self.max_location.load(Ordering::Relaxed).as_ref() }
+    }
+
+    fn reset(&self) {
+        self.max.store(0, Ordering::Relaxed);
+        self.max_location
+            .store(core::ptr::null_mut(), Ordering::Relaxed);
+    }
+}
+
+/// Per-CPU data for the latency tracing.
+pub(crate) struct TraceData {
+    #[cfg(feature = "irqsoff-trace")]
+    irqs_off: SectionData,
+    #[cfg(feature = "irqsoff-trace")]
+    histogram: [AtomicUsize; HIST_BUCKETS],
+    #[cfg(feature = "preemptoff-trace")]
+    preempt_off: SectionData,
+    #[cfg(feature = "preemptoff-trace")]
+    preempt_off_count: AtomicUsize,
+}
+
+impl TraceData {
+    pub(crate) const fn new() -> Self {
+        Self {
+            #[cfg(feature = "irqsoff-trace")]
+            irqs_off: SectionData::new(),
+            #[cfg(feature = "irqsoff-trace")]
+            histogram: [const { AtomicUsize::new(0) }; HIST_BUCKETS],
+            #[cfg(feature = "preemptoff-trace")]
+            preempt_off: SectionData::new(),
+            #[cfg(feature = "preemptoff-trace")]
+            preempt_off_count: AtomicUsize::new(0),
+        }
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            #[cfg(feature = "irqsoff-trace")]
+            irqs_off: IrqsOffTrace {
+                histogram: core::array::from_fn(|i| self.histogram[i].load(Ordering::Relaxed)),
+                max: self.irqs_off.max.load(Ordering::Relaxed),
+                max_location: self.irqs_off.max_location(),
+            },
+            #[cfg(feature = "preemptoff-trace")]
+            preempt_off: PreemptOffTrace {
+                count: self.preempt_off_count.load(Ordering::Relaxed),
+                max: self.preempt_off.max.load(Ordering::Relaxed),
+                max_location: self.preempt_off.max_location(),
+            },
+        }
+    }
+
+    fn reset(&self) {
+        #[cfg(feature = "irqsoff-trace")]
+        {
+            self.irqs_off.reset();
+            for bucket in &self.histogram {
+                bucket.store(0, Ordering::Relaxed);
+            }
+        }
+        #[cfg(feature = "preemptoff-trace")]
+        {
+            self.preempt_off.reset();
+            self.preempt_off_count.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Returns the tracing results of the current CPU.
+pub fn snapshot() -> Snapshot {
+    with_local(|data| data.trace.snapshot())
+}
+
+/// Returns the tracing results of the CPU that owns `data`.
+///
+/// It can be called on any CPU, but the results may be inconsistent if that
+/// CPU is updating them at the same time.
+pub fn snapshot_of(data: &PerCpuData) -> Snapshot {
+    data.trace.snapshot()
+}
+
+/// Clears the tracing results of the current CPU.
+///
+/// The sections that have been entered are still measured when they end.
+pub fn reset() {
+    with_local(|data| data.trace.reset())
+}
+
+/// Clears the tracing results of the CPU that owns `data`.
+///
+/// It can be called on any CPU, but some results may be kept if that CPU is
+/// updating them at the same time.
+pub fn reset_of(data: &PerCpuData) {
+    data.trace.reset()
+}
+
+/// Whether a guard of `kind` at the nesting depth `depth` starts or ends a
+/// measured section. `irqs_enabled` is whether local IRQs are enabled outside
+/// the guard.
+fn is_outermost(kind: GuardKind, depth: usize, irqs_enabled: bool) -> bool {
+    match kind {
+        GuardKind::Irq => irqs_enabled,
+        GuardKind::Preempt => depth == 1,
+    }
+}
+
+/// Starts measuring if a guard of `kind` acquired at `location`, which makes
+/// the nesting depth `depth`, enters a section.
+#[inline]
+pub(crate) fn on_acquire(
+    data: &TraceData,
+    kind: GuardKind,
+    depth: usize,
+    irqs_enabled: bool,
+    location: &'static Location<'static>,
+) {
+    if !is_outermost(kind, depth, irqs_enabled) {
+        return;
+    }
+    match kind {
+        #[cfg(feature = "irqsoff-trace")]
+        GuardKind::Irq => data.irqs_off.enter(location),
+        #[cfg(feature = "preemptoff-trace")]
+        GuardKind::Preempt => data.preempt_off.enter(location),
+        #[allow(unreachable_patterns)]
+        _ => {}
+    }
+}
+
+/// Ends the measurement if a guard of `kind` released at the nesting depth
+/// `depth` leaves a section, and returns the duration of the section and where
+/// it was entered.
+///
+/// The timestamp is only read for the sections that are actually measured.
+#[inline]
+pub(crate) fn on_release(
+    data: &TraceData,
+    kind: GuardKind,
+    depth: usize,
+    irqs_enabled: bool,
+) -> Option<(usize, Option<&'static Location<'static>>)> {
+    if !is_outermost(kind, depth, irqs_enabled) {
+        return None;
+    }
+    match kind {
+        #[cfg(feature = "irqsoff-trace")]
+        GuardKind::Irq => {
+            let (duration, location) = data.irqs_off.exit();
+            let bucket = &data.histogram[duration.checked_ilog2().unwrap_or(0) as usize];
+            bucket.store(
+                bucket.load(Ordering::Relaxed).wrapping_add(1),
+                Ordering::Relaxed,
+            );
+            Some((duration, location))
+        }
+        #[cfg(feature = "preemptoff-trace")]
+        GuardKind::Preempt => {
+            let count = data.preempt_off_count.load(Ordering::Relaxed);
+            data.preempt_off_count
+                .store(count.wrapping_add(1), Ordering::Relaxed);
+            Some(data.preempt_off.exit())
+        }
+        #[allow(unreachable_patterns)]
+        _ => None,
+    }
+}
@@ -0,0 +1,137 @@
+//! Time budgets of the critical sections, enabled by the feature `watchdog`.
+
+use core::panic::Location;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use core::time::Duration;
+
+use crate::guard::GuardKind;
+
+/// A handler called when a critical section lasts longer than its budget.
+///
+/// The arguments are the guard kind, the elapsed time, and where the guard of
+/// the outermost section was created.
+pub type BudgetHandler = fn(GuardKind, Duration, &'static Location<'static>);
+
+/// Budget of each guard kind in microseconds, [`usize::MAX`] for no budget.
+static BUDGETS: [AtomicUsize; GuardKind::COUNT] =
+    [const { AtomicUsize::new(usize::MAX) }; GuardKind::COUNT];
+
+static TIMESTAMP_FREQUENCY: AtomicUsize = AtomicUsize::new(0);
+
+static BUDGET_HANDLER: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+fn set_budget(kind: GuardKind, budget: Duration) {
+    let micros = usize::try_from(budget.as_micros()).unwrap_or(usize::MAX);
+    BUDGETS[kind as usize].store(micros, Ordering::Relaxed);
+}
+
+/// Sets the budget of the outermost sections with local IRQs disabled by
+/// [`IrqSave`](crate::IrqSave), with a resolution of microseconds.
+/// [`Duration::MAX`] removes the budget, which is the default.
+///
+/// # Examples
+///
+/// ```
+/// use core::sync::atomic::{AtomicBool, Ordering};
+/// use core::time::Duration;
+/// use kernel_guard::{GuardKind, IrqSave};
+///
+/// static EXCEEDED: AtomicBool = AtomicBool::new(false);
+///
+/// kernel_guard::set_budget_handler(|kind, elapsed, location| {
+///     assert_eq!(kind, GuardKind::Irq);
+///     assert!(elapsed >= Duration::from_millis(10));
+///     assert_eq!(location.file(), file!());
+///     EXCEEDED.store(true, Ordering::Relaxed);
+/// });
+/// kernel_guard::set_irqsoff_budget(Duration::from_millis(10));
+///
+/// drop(IrqSave::new());
+/// assert!(!EXCEEDED.load(Ordering::Relaxed));
+///
+/// let guard = IrqSave::new();
+/// std::thread::sleep(Duration::from_millis(20));
+/// drop(guard);
+/// assert!(EXCEEDED.load(Ordering::Relaxed));
+/// ```
+pub fn set_irqsoff_budget(budget: Duration) {
+    set_budget(GuardKind::Irq, budget);
+}
+
+/// Sets the budget of the outermost sections with kernel preemption disabled
+/// by the guards, with a resolution of microseconds. [`Duration::MAX`] removes
+/// the budget, which is the default.
+///
+/// # Examples
+///
+/// ```
+/// use core::time::Duration;
+/// use kernel_guard::NoPreempt;
+///
+/// kernel_guard::set_budget_handler(|kind, _elapsed, location| {
+///     panic!("{kind:?} guard created at {location} exceeded its budget");
+/// });
+/// kernel_guard::set_preemptoff_budget(Duration::from_secs(1));
+/// drop(NoPreempt::new());
+/// ```
+///
+/// ```should_panic
+/// use core::time::Duration;
+/// use kernel_guard::NoPreempt;
+///
+/// kernel_guard::set_budget_handler(|kind, _elapsed, location| {
+///     panic!("{kind:?} guard created at {location} exceeded its budget");
+/// });
+/// kernel_guard::set_preemptoff_budget(Duration::ZERO);
+/// let guard = NoPreempt::new();
+/// std::thread::sleep(Duration::from_millis(1));
+/// drop(guard);
+/// ```
+pub fn set_preemptoff_budget(budget: Duration) {
+    set_budget(GuardKind::Preempt, budget);
+}
+
+/// Registers the handler for critical sections exceeding their budgets,
+/// replacing the previously registered one.
+///
+/// The handler is called on the CPU that releases the guard of the outermost
+/// section, before IRQs or preemption are enabled again. Before any handler is
+/// registered, the budgets are not checked.
+pub fn set_budget_handler(handler: BudgetHandler) {
+    BUDGET_HANDLER.store(handler as *mut (), Ordering::Release);
+}
+
+/// Sets the frequency of the timestamp source in Hz.
+///
+/// It must be called before the budgets can be checked on x86 and RISC-V. On
+/// AArch64 the frequency is read from `CNTFRQ_EL0` by default, and on hosted
+/// targets (not `target_os = "none"`) the timestamps are in nanoseconds with
+/// the feature `std`.
+pub fn set_timestamp_frequency(hz: usize) {
+    TIMESTAMP_FREQUENCY.store(hz, Ordering::Relaxed);
+}
+
+fn timestamp_frequency() -> usize {
+    match TIMESTAMP_FREQUENCY.load(Ordering::Relaxed) {
+        0 => crate::arch::default_timestamp_frequency(),
+        hz => hz,
+    }
+}
+
+/// Checks the outermost section of `kind` entered at `location`, which lasted
+/// `ticks` of the timestamp source.
+pub(crate) fn check(kind: GuardKind, ticks: usize, location: &'static Location<'static>) {
+    let budget = BUDGETS[kind as usize].load(Ordering::Relaxed);
+    let handler = BUDGET_HANDLER.load(Ordering::Acquire);
+    let hz = timestamp_frequency();
+    if budget == usize::MAX || handler.is_null() || hz == 0 {
+        return;
+    }
+    let nanos = ticks as u128 * 1_000_000_000 / hz as u128;
+    if nanos > budget as u128 * 1000 {
+        let nanos = u64::try_from(nanos).unwrap_or(u64::MAX);
+        // SAFETY: the pointer is not null, so it comes from a `BudgetHandler`.
+        let handler: BudgetHandler = unsafe { core::mem::transmute(handler) };
+        handler(kind, Duration::from_nanos(nanos), location);
+    }
+}
@@ -0,0 +1,39 @@
+//! The assertion guards panic only in debug builds, while borrowing a token
+//! from them panics in all builds. It can not be checked by doctests since
+//! they are always built with debug assertions.
+
+use kernel_guard::{AssertIrqsDisabled, AssertPreemptDisabled, IrqSave};
+
+#[test]
+#[cfg_attr(debug_assertions, should_panic(expected = "IRQs are not disabled"))]
+fn assert_irqs_disabled_with_irqs_enabled() {
+    let _guard = AssertIrqsDisabled::new();
+}
+
+#[test]
+#[cfg_attr(
+    all(debug_assertions, feature = "preempt-count"),
+    should_panic(expected = "kernel preemption is not disabled")
+)]
+fn assert_preempt_disabled_with_preemption_enabled() {
+    let _guard = AssertPreemptDisabled::new();
+}
+
+#[test]
+#[should_panic(expected = "IRQs are not disabled")]
+fn irqs_disabled_token_with_irqs_enabled() {
+    let irq_guard = IrqSave::new();
+    let guard = AssertIrqsDisabled::new();
+    drop(irq_guard);
+    let _token = guard.irqs_disabled();
+}
+
+#[test]
+#[cfg_attr(
+    feature = "preempt",
+    should_panic(expected = "kernel preemption is not disabled")
+)]
+fn preempt_disabled_token_with_preemption_enabled() {
+    let guard = AssertPreemptDisabled::new();
+    let _token = guard.preempt_disabled();
+}
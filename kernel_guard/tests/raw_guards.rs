@@ -0,0 +1,29 @@
+//! On hosted targets each thread acts as a CPU, so a raw guard state moved to
+//! another thread is released on another CPU, which panics in debug builds
+//! with the feature `percpu`.
+
+#![cfg(all(debug_assertions, feature = "percpu"))]
+
+use std::thread;
+
+use kernel_guard::{IrqSave, NoPreempt};
+
+#[test]
+fn irq_state_released_on_another_cpu() {
+    let state = unsafe { IrqSave::new().into_raw() };
+    let res = thread::spawn(move || drop(unsafe { IrqSave::from_raw(state) })).join();
+    let msg = *res.unwrap_err().downcast::<&str>().unwrap();
+    assert_eq!(
+        msg,
+        "guard state released on another CPU than where it was saved"
+    );
+    drop(unsafe { IrqSave::from_raw(state) });
+}
+
+#[test]
+fn preempt_state_released_on_another_cpu() {
+    let state = unsafe { NoPreempt::new().into_raw() };
+    let res = thread::spawn(move || drop(unsafe { NoPreempt::from_raw(state) })).join();
+    assert!(res.is_err());
+    drop(unsafe { NoPreempt::from_raw(state) });
+}